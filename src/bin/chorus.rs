@@ -1,15 +1,12 @@
-use chorus::config::{Config, FriendlyConfig};
-use chorus::counting_stream::CountingStream;
 use chorus::error::Error;
 use chorus::globals::GLOBALS;
-use chorus::ip::HashedPeer;
 use std::env;
-use std::fs::OpenOptions;
-use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::signal::unix::{signal, SignalKind};
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -28,29 +25,78 @@ async fn main() -> Result<(), Error> {
     // Log host name
     log::info!(target: "Server", "HOSTNAME = {}", config.hostname);
 
+    // TLS setup
+    if config.listeners.iter().any(|l| l.tls) {
+        log::info!(target: "Server", "Using TLS");
+        *GLOBALS.tls_acceptor.write() = Some(chorus::tls::tls_acceptor(&config)?);
+    } else {
+        log::info!(target: "Server", "Not using TLS");
+    }
+
+    // Take over the listeners of a running chorus, if there is one. This must happen
+    // before we open the store, which it releases before handing over.
+    let handed_over = match config.handoff_socket {
+        Some(ref path) => chorus::handoff::request_handoff(path).await?,
+        None => None,
+    };
+    let passed_sockets = match handed_over {
+        Some(fds) => fds,
+        None => chorus::listener::systemd_sockets(),
+    };
+
     chorus::setup_store(&config)?;
+    chorus::rotate_ip_hash_key(&config)?;
 
     if let Some(ref blossom_directory) = config.blossom_directory {
         let filestore = chorus::filestore::FileStore::new(blossom_directory).await?;
         let _ = GLOBALS.filestore.set(filestore);
     }
 
-    // TLS setup
-    let maybe_tls_acceptor = if config.use_tls {
-        log::info!(target: "Server", "Using TLS");
-        Some(chorus::tls::tls_acceptor(&config)?)
-    } else {
-        log::info!(target: "Server", "Not using TLS");
-        None
+    // Bind our listeners, or use those passed to us
+    let listeners = chorus::listener::setup_listeners(&config, passed_sockets).await?;
+
+    // Listen for a new chorus process to hand our listeners to
+    let mut handoff_requests = match config.handoff_socket {
+        Some(ref path) => chorus::handoff::listen_for_handoff(path)?,
+        None => tokio::sync::mpsc::channel(1).1,
     };
+    let mut handoff: Option<UnixStream> = None;
 
-    // Bind listener to port
-    let listener = TcpListener::bind((&*config.ip_address, config.port)).await?;
-    log::info!(target: "Server", "Running on {}:{}", config.ip_address, config.port);
+    // Bind the metrics listener, if configured
+    if let Some(ref address) = config.metrics_listen_address {
+        let metrics_listener = TcpListener::bind(address.as_str()).await?;
+        log::info!(target: "Server", "Serving metrics on {}", address);
+        tokio::spawn(chorus::web::metrics::serve_metrics(metrics_listener));
+    }
 
     // Store config into GLOBALS
     *GLOBALS.config.write() = config;
 
+    // Hourly upkeep: rotate the IP hash key when it is due, and remove IP data that
+    // is no longer of use
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let config = GLOBALS.config.read().clone();
+            if let Err(e) = chorus::rotate_ip_hash_key(&config) {
+                log::error!(target: "Server", "Could not rotate the IP hash key: {}", e);
+            }
+            match chorus::compact_ip_data(config.ip_reputation_half_life_days * 86400) {
+                Ok(0) => {}
+                Ok(n) => log::info!(target: "Server", "Removed {} stale IP records", n),
+                Err(e) => log::error!(target: "Server", "Could not compact IP data: {}", e),
+            }
+        }
+    });
+
+    // Start accepting connections on each listener
+    let accept_tasks: Vec<_> = listeners
+        .iter()
+        .map(|l| tokio::spawn(chorus::listener::accept_connections(l.clone())))
+        .collect();
+
     let mut interrupt_signal = signal(SignalKind::interrupt())?;
     let mut quit_signal = signal(SignalKind::quit())?;
     let mut terminate_signal = signal(SignalKind::terminate())?;
@@ -72,74 +118,56 @@ async fn main() -> Result<(), Error> {
                 break;
             },
 
+            // Hand our listeners to a new chorus process, then shut down
+            Some(stream) = handoff_requests.recv() => {
+                log::info!(target: "Server", "Handing off to a new chorus process");
+                handoff = Some(stream);
+                break;
+            },
+
             // Reload config on HUP
             v = hup_signal.recv() => if v.is_some() {
                 log::info!(target: "Server", "SIGHUP: Reloading configuration");
 
-                // Reload the config file
-                let mut file = OpenOptions::new().read(true).open(config_path.clone())?;
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                let friendly_config: FriendlyConfig = toml::from_str(&contents)?;
-                let config: Config = friendly_config.into_config()?;
-
-                *GLOBALS.config.write() = config;
+                // Reload the config file, keeping the running config if it is broken
+                if let Err(e) = chorus::reload_config(&config_path) {
+                    log::error!(target: "Server", "Config not reloaded: {}", e);
+                }
 
                 chorus::print_stats();
             },
+        };
+    }
 
-            // Accepts network connections and spawn a task to serve each one
-            v = listener.accept() => {
-                let (tcp_stream, hashed_peer) = {
-                    let (tcp_stream, peer_addr) = v?;
-                    let hashed_peer = HashedPeer::new(peer_addr);
-                    (tcp_stream, hashed_peer)
-                };
-
-                // Possibly IP block early
-                if ! GLOBALS.config.read().chorus_is_behind_a_proxy
-                    && GLOBALS.config.read().enable_ip_blocking
-                {
-                    let ip_data = chorus::get_ip_data(hashed_peer.ip())?;
-                    if ip_data.is_banned() {
-                        log::debug!(target: "Client",
-                                    "{}: Blocking reconnection until {}",
-                                    hashed_peer.ip(),
-                                    ip_data.ban_until);
-                        // note: no need to shutdown() which only drops the write half.
-                        // the whole thing gets dropped when we continue.
-                        continue;
-                    }
-                }
+    // Stop accepting connections
+    for task in accept_tasks {
+        task.abort();
+    }
 
-                let counting_stream = CountingStream(tcp_stream);
-
-                let maybe_tls_acceptor_clone = maybe_tls_acceptor.clone();
-                tokio::spawn(async move {
-                    match maybe_tls_acceptor_clone {
-                        Some(tls_acceptor) => {
-                            match tls_acceptor.accept(counting_stream).await {
-                                Ok(stream) => {
-                                    let io = hyper_util::rt::TokioIo::new(stream);
-                                    chorus::serve(io, hashed_peer).await;
-                                },
-                                Err(e) => {
-                                    log::error!(
-                                        target: "Client",
-                                        "{}: TLS accept: {}", hashed_peer, e
-                                    );
-                                }
-                            }
-                        },
-                        None => {
-                            let io = hyper_util::rt::TokioIo::new(counting_stream);
-                            chorus::serve(io, hashed_peer).await;
-                        }
-                    };
-                });
+    // Hand our listeners to the new process at once, then keep serving our websockets
+    // for a while. The new process opens the store once we have exited.
+    if let Some(ref stream) = handoff {
+        let fds: Vec<_> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+        match chorus::handoff::send_listeners(stream, &fds) {
+            Ok(()) => {
+                log::info!(target: "Server", "Listeners handed off.");
+                let drain = GLOBALS.config.read().handoff_drain_seconds;
+                let num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
+                if num_connections != 0 {
+                    log::info!(target: "Server", "Serving {num_connections} websockets for up to {drain}s...");
+                    wait_for_websockets(
+                        Duration::from_secs(drain),
+                        &mut interrupt_signal,
+                        &mut quit_signal,
+                        &mut terminate_signal,
+                    )
+                    .await;
+                }
             }
-        };
+            Err(e) => log::error!(target: "Server", "Handoff failed: {}", e),
+        }
     }
+    drop(listeners);
 
     // Pre-sync in case something below hangs up
     let _ = GLOBALS.store.get().unwrap().sync();
@@ -148,38 +176,18 @@ async fn main() -> Result<(), Error> {
     let _ = GLOBALS.shutting_down.send(true);
 
     // Wait for active websockets to shutdown gracefully
-    let mut num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
+    let num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
     if num_connections != 0 {
         log::info!(target: "Server", "Waiting for {num_connections} websockets to shutdown...");
-
-        // We will check if all clients have shutdown every 50ms
-        let interval = tokio::time::interval(Duration::from_millis(50));
-        tokio::pin!(interval);
-
-        let mut ms = 0;
-
-        while num_connections != 0 {
-            // If we get another shutdown signal, stop waiting for websockets
-            tokio::select! {
-                v = interrupt_signal.recv() => if v.is_some() {
-                    break;
-                },
-                v = quit_signal.recv() => if v.is_some() {
-                    break;
-                },
-                v = terminate_signal.recv() => if v.is_some() {
-                    break;
-                },
-                _instant = interval.tick() => {
-                    ms += 50;
-                    if ms > 5_000 {
-                        log::info!(target: "Server", "Some connections were hung.");
-                        break;
-                    }
-                    num_connections = GLOBALS.num_connections.load(Ordering::Relaxed);
-                    continue;
-                }
-            }
+        let closed = wait_for_websockets(
+            Duration::from_secs(5),
+            &mut interrupt_signal,
+            &mut quit_signal,
+            &mut terminate_signal,
+        )
+        .await;
+        if !closed {
+            log::info!(target: "Server", "Some connections were hung.");
         }
     }
 
@@ -188,5 +196,43 @@ async fn main() -> Result<(), Error> {
     log::info!(target: "Server", "Syncing and shutting down.");
     let _ = GLOBALS.store.get().unwrap().sync();
 
+    // The new process takes over once we exit, closing the handoff connection
+    drop(handoff);
+
     Ok(())
 }
+
+// Wait until all websockets have closed, for at most `limit`. Another exit-type
+// signal stops waiting. Returns whether they all closed.
+async fn wait_for_websockets(
+    limit: Duration,
+    interrupt_signal: &mut Signal,
+    quit_signal: &mut Signal,
+    terminate_signal: &mut Signal,
+) -> bool {
+    // We will check if all clients have shutdown every 50ms
+    let mut interval = tokio::time::interval(Duration::from_millis(50));
+    let deadline = tokio::time::Instant::now() + limit;
+
+    loop {
+        tokio::select! {
+            v = interrupt_signal.recv() => if v.is_some() {
+                return false;
+            },
+            v = quit_signal.recv() => if v.is_some() {
+                return false;
+            },
+            v = terminate_signal.recv() => if v.is_some() {
+                return false;
+            },
+            _instant = interval.tick() => {
+                if GLOBALS.num_connections.load(Ordering::Relaxed) == 0 {
+                    return true;
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    return false;
+                }
+            }
+        }
+    }
+}
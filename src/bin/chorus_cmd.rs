@@ -1,8 +1,12 @@
 use chorus::error::{ChorusError, Error};
+use chorus::export::{ImportMode, ModerationState};
 use chorus::globals::GLOBALS;
-use pocket_db::ScreenResult;
-use pocket_types::{Filter, Id, Pubkey, Tags};
+use chorus::ip::{parse_hashed_ip, subnet_of, HashedIp};
+use pocket_types::{Id, Pubkey, Time};
 use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
 
 const USAGE: &str = "usage: chorus_cmd <config_path> <command> [args...]";
 
@@ -24,6 +28,7 @@ fn main() -> Result<(), Error> {
 
     chorus::setup_logging(&config);
     chorus::setup_store(&config)?;
+    *GLOBALS.config.write() = config;
 
     // Handle command
     let command = args
@@ -35,8 +40,11 @@ fn main() -> Result<(), Error> {
                 .next()
                 .ok_or::<Error>(ChorusError::General("ID argument missing".to_owned()).into())?;
             let id: Id = Id::read_hex(idstr.as_bytes())?;
-            GLOBALS.store.get().unwrap().remove_event(id)?;
-            println!("Done.");
+            if chorus::remove_events(&[id]).remove(0)? {
+                println!("Done.");
+            } else {
+                println!("Not found.");
+            }
         }
         "delete_by_pubkey" => {
             let pubstr = args.next().ok_or::<Error>(
@@ -44,19 +52,8 @@ fn main() -> Result<(), Error> {
             )?;
             let pk: Pubkey = Pubkey::read_hex(pubstr.as_bytes())?;
 
-            let mut tags_buffer: [u8; 128] = [0; 128];
-            let (_, tags) = Tags::from_json(b"[]", &mut tags_buffer)?;
-            let mut filter_buffer: [u8; 128] = [0; 128];
-            let filter =
-                Filter::from_parts(&[], &[pk], &[], tags, None, None, None, &mut filter_buffer)?;
-            let (events, _redacted) =
-                GLOBALS
-                    .store
-                    .get()
-                    .unwrap()
-                    .find_events(filter, true, 0, 0, |_| ScreenResult::Match)?;
-            for event in events.iter() {
-                GLOBALS.store.get().unwrap().remove_event(event.id())?;
+            for id in chorus::get_event_ids_by_pubkey(pk)?.drain(..) {
+                GLOBALS.store.get().unwrap().remove_event(id)?;
             }
             println!("Done.");
         }
@@ -98,6 +95,148 @@ fn main() -> Result<(), Error> {
 
             chorus::rm_authorized_user(pk)?;
         }
+        "batch" => {
+            let operation = args.next().ok_or::<Error>(
+                ChorusError::General("Batch operation argument missing".to_owned()).into(),
+            )?;
+
+            // Read one hex id or pubkey per line, from a file or stdin
+            let mut contents = String::new();
+            match args.next() {
+                Some(path) if path != "-" => {
+                    File::open(path)?.read_to_string(&mut contents)?;
+                }
+                _ => {
+                    io::stdin().read_to_string(&mut contents)?;
+                }
+            }
+            let lines: Vec<&str> = contents
+                .lines()
+                .map(|l| l.trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .collect();
+
+            match &*operation {
+                "ban_event" | "allow_event" | "clear_event" | "delete_by_id" => {
+                    let mut ids: Vec<Id> = Vec::new();
+                    for line in lines.iter() {
+                        match Id::read_hex(line.as_bytes()) {
+                            Ok(id) => ids.push(id),
+                            Err(_) => println!("{line}: could not be parsed"),
+                        }
+                    }
+                    if operation == "delete_by_id" {
+                        for (id, outcome) in ids.iter().zip(chorus::remove_events(&ids)) {
+                            match outcome {
+                                Ok(true) => {}
+                                Ok(false) => println!("{id}: not found"),
+                                Err(e) => println!("{id}: {e}"),
+                            }
+                        }
+                    } else {
+                        let approval = match &*operation {
+                            "ban_event" => Some(false),
+                            "allow_event" => Some(true),
+                            _ => None,
+                        };
+                        chorus::batch_event_approval(&ids, approval, "")?;
+                    }
+                    println!("Done with {} events.", ids.len());
+                }
+                "ban_pubkey" | "allow_pubkey" | "clear_pubkey" => {
+                    let mut pubkeys: Vec<Pubkey> = Vec::new();
+                    for line in lines.iter() {
+                        match Pubkey::read_hex(line.as_bytes()) {
+                            Ok(pk) => pubkeys.push(pk),
+                            Err(_) => println!("{line}: could not be parsed"),
+                        }
+                    }
+                    let approval = match &*operation {
+                        "ban_pubkey" => Some(false),
+                        "allow_pubkey" => Some(true),
+                        _ => None,
+                    };
+                    chorus::batch_pubkey_approval(&pubkeys, approval, "")?;
+                    println!("Done with {} pubkeys.", pubkeys.len());
+                }
+                _ => {
+                    return Err(ChorusError::General("Unknown batch operation.".to_owned()).into());
+                }
+            }
+        }
+        "inspect_ip" | "reset_ip" => {
+            let text = args
+                .next()
+                .ok_or::<Error>(ChorusError::General("IP argument missing".to_owned()).into())?;
+            let (mut ips, _prefix_len) = parse_hashed_ip(&text).ok_or::<Error>(
+                ChorusError::General("IP address could not be parsed".to_owned()).into(),
+            )?;
+
+            // An address shares in the record of its subnet
+            if let Ok(ipaddr) = text.parse::<IpAddr>() {
+                if let Some(subnet) = subnet_of(ipaddr) {
+                    ips.push(HashedIp::new_network(subnet));
+                }
+            }
+
+            if command == "inspect_ip" {
+                let now = Time::now().as_u64();
+                let half_life_seconds = GLOBALS.config.read().ip_reputation_half_life_days * 86400;
+                for ip in ips.iter() {
+                    let mut ip_data = chorus::get_ip_data(*ip)?;
+                    ip_data.decay_to(now, half_life_seconds);
+                    let r = &ip_data.reputation;
+                    println!(
+                        "{ip}: good={:.2} errored={:.2} too_many_errors={:.2} timed_out={:.2} multiplier={:.2} ban_until={}{}",
+                        r.good,
+                        r.errored,
+                        r.too_many_errors,
+                        r.timed_out,
+                        r.ban_multiplier(),
+                        ip_data.ban_until,
+                        if ip_data.is_banned() { " (banned)" } else { "" }
+                    );
+                }
+            } else {
+                for ip in ips.iter() {
+                    if chorus::delete_ip_data(*ip)? {
+                        println!("{ip}: reset");
+                    }
+                }
+                println!("Done.");
+            }
+        }
+        "export" => {
+            let state = chorus::export::export_state()?;
+            let json = serde_json::to_string_pretty(&state)?;
+            match args.next() {
+                Some(path) if path != "-" => {
+                    let mut file = File::create(path)?;
+                    file.write_all(json.as_bytes())?;
+                    file.write_all(b"\n")?;
+                }
+                _ => println!("{json}"),
+            }
+        }
+        "import" => {
+            let mode: ImportMode = args
+                .next()
+                .ok_or::<Error>(ChorusError::General("Import mode missing".to_owned()).into())?
+                .parse()?;
+
+            let mut contents = String::new();
+            match args.next() {
+                Some(path) if path != "-" => {
+                    File::open(path)?.read_to_string(&mut contents)?;
+                }
+                _ => {
+                    io::stdin().read_to_string(&mut contents)?;
+                }
+            }
+            let state: ModerationState = serde_json::from_str(&contents)?;
+            let count = chorus::export::import_state(&state, mode)?;
+            println!("Imported {count} entries.");
+        }
         _ => {
             return Err(ChorusError::General("Unknown command.".to_owned()).into());
         }
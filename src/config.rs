@@ -1,18 +1,55 @@
-use crate::error::Error;
+use crate::error::{ChorusError, Error};
+use crate::forwarded::ProxyIpHeader;
+use crate::ip::IpCidr;
 use hyper::http::uri::{Authority, Scheme, Uri};
 use pocket_types::Pubkey;
 use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use url::Host;
 
+/// A listener as written in the config file. Unset settings default to `use_tls` and
+/// `chorus_is_behind_a_proxy`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FriendlyListener {
+    pub address: String,
+    pub tls: Option<bool>,
+    pub behind_proxy: Option<bool>,
+    pub proxy_protocol: bool,
+}
+
+/// An address to listen on: "host:port", or "unix:<path>" for a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub tls: bool,
+    pub behind_proxy: bool,
+    pub proxy_protocol: bool,
+    /// Made from ip_address and port because no listeners are configured
+    pub implicit: bool,
+}
+
+impl ListenerConfig {
+    /// The path, if this is a Unix domain socket
+    pub fn unix_path(&self) -> Option<&str> {
+        self.address.strip_prefix("unix:")
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct FriendlyConfig {
     pub data_directory: String,
     pub ip_address: String,
     pub port: u16,
+    pub handoff_socket: Option<String>,
+    pub handoff_drain_seconds: u64,
+    pub listeners: Vec<FriendlyListener>,
     pub hostname: String,
     pub chorus_is_behind_a_proxy: bool,
+    pub trusted_proxies: Vec<String>,
+    pub proxy_ip_header: String,
     pub base_url: Option<String>,
     pub use_tls: bool,
     pub certchain_pem_path: String,
@@ -41,12 +78,27 @@ pub struct FriendlyConfig {
     pub client_log_level: String,
     pub enable_ip_blocking: bool,
     pub minimum_ban_seconds: u64,
+    pub ip_reputation_half_life_days: u64,
+    pub ip_hash_key_rotation_days: u64,
     pub timeout_seconds: u64,
     pub max_connections_per_ip: usize,
+    pub subnet_prefix_len_v4: u8,
+    pub subnet_prefix_len_v6: u8,
+    pub max_connections_per_subnet: usize,
     pub throttling_bytes_per_second: usize,
     pub throttling_burst: usize,
+    pub websocket_compression: bool,
+    pub websocket_compression_window_bits: u8,
+    pub websocket_compression_min_bytes: usize,
     pub blossom_directory: Option<String>,
     pub enable_negentropy: bool,
+    pub metrics_listen_address: Option<String>,
+    pub public_health_endpoints: bool,
+    pub metrics_event_kinds: Vec<u16>,
+    pub log_format: String,
+    pub log_file: Option<String>,
+    pub log_file_max_bytes: u64,
+    pub log_file_keep: usize,
 }
 
 impl Default for FriendlyConfig {
@@ -55,8 +107,13 @@ impl Default for FriendlyConfig {
             data_directory: "/opt/chorus/var/chorus".to_string(),
             ip_address: "127.0.0.1".to_string(),
             port: 443,
+            handoff_socket: None,
+            handoff_drain_seconds: 10,
+            listeners: Vec::new(),
             hostname: "localhost".to_string(),
             chorus_is_behind_a_proxy: false,
+            trusted_proxies: Vec::new(),
+            proxy_ip_header: "x-real-ip".to_string(),
             base_url: None,
             use_tls: true,
             certchain_pem_path: "/opt/chorus/etc/tls/fullchain.pem".to_string(),
@@ -84,12 +141,27 @@ impl Default for FriendlyConfig {
             client_log_level: "Info".to_string(),
             enable_ip_blocking: true,
             minimum_ban_seconds: 1,
+            ip_reputation_half_life_days: 7,
+            ip_hash_key_rotation_days: 0,
             timeout_seconds: 60,
             max_connections_per_ip: 5,
+            subnet_prefix_len_v4: 24,
+            subnet_prefix_len_v6: 64,
+            max_connections_per_subnet: 20,
             throttling_bytes_per_second: 1024 * 1024,
             throttling_burst: 1024 * 1024 * 16,
+            websocket_compression: true,
+            websocket_compression_window_bits: 15,
+            websocket_compression_min_bytes: 256,
             blossom_directory: None,
             enable_negentropy: false,
+            metrics_listen_address: None,
+            public_health_endpoints: false,
+            metrics_event_kinds: vec![0, 1, 3, 5, 6, 7, 16, 1059, 1111, 1984, 9735, 10002, 30023],
+            log_format: "text".to_string(),
+            log_file: None,
+            log_file_max_bytes: 100 * 1024 * 1024,
+            log_file_keep: 5,
         }
     }
 }
@@ -100,8 +172,13 @@ impl FriendlyConfig {
             data_directory,
             ip_address,
             port,
+            handoff_socket,
+            handoff_drain_seconds,
+            listeners,
             hostname,
             chorus_is_behind_a_proxy,
+            trusted_proxies,
+            proxy_ip_header,
             base_url,
             use_tls,
             certchain_pem_path,
@@ -129,12 +206,27 @@ impl FriendlyConfig {
             client_log_level,
             enable_ip_blocking,
             minimum_ban_seconds,
+            ip_reputation_half_life_days,
+            ip_hash_key_rotation_days,
             timeout_seconds,
             max_connections_per_ip,
+            subnet_prefix_len_v4,
+            subnet_prefix_len_v6,
+            max_connections_per_subnet,
             throttling_bytes_per_second,
             throttling_burst,
+            websocket_compression,
+            websocket_compression_window_bits,
+            websocket_compression_min_bytes,
             blossom_directory,
             enable_negentropy,
+            metrics_listen_address,
+            public_health_endpoints,
+            metrics_event_kinds,
+            log_format,
+            log_file,
+            log_file_max_bytes,
+            log_file_keep,
         } = self;
 
         let mut contact_public_key: Option<Pubkey> = None;
@@ -149,6 +241,76 @@ impl FriendlyConfig {
 
         let hostname = Host::parse(&hostname)?;
 
+        let trusted_proxies: Vec<IpCidr> = trusted_proxies
+            .iter()
+            .map(|s| s.parse::<IpCidr>())
+            .collect::<Result<_, Error>>()?;
+
+        let proxy_ip_header = proxy_ip_header.parse::<ProxyIpHeader>()?;
+
+        // Check the subnet prefix lengths are valid
+        IpCidr::new(Ipv4Addr::UNSPECIFIED.into(), subnet_prefix_len_v4)?;
+        IpCidr::new(Ipv6Addr::UNSPECIFIED.into(), subnet_prefix_len_v6)?;
+
+        if !(9..=15).contains(&websocket_compression_window_bits) {
+            return Err(ChorusError::General(
+                "websocket_compression_window_bits must be from 9 to 15".to_owned(),
+            )
+            .into());
+        }
+
+        if handoff_drain_seconds > crate::handoff::MAX_DRAIN_SECONDS {
+            return Err(ChorusError::General(format!(
+                "handoff_drain_seconds must be at most {}",
+                crate::handoff::MAX_DRAIN_SECONDS
+            ))
+            .into());
+        }
+
+        let log_format = log_format.to_ascii_lowercase();
+        if log_format != "text" && log_format != "json" {
+            return Err(ChorusError::General("log_format must be text or json".to_owned()).into());
+        }
+
+        // Without any listeners configured, listen on ip_address and port
+        let listeners: Vec<ListenerConfig> = if listeners.is_empty() {
+            let address = if ip_address.contains(':') {
+                format!("[{}]:{}", ip_address, port)
+            } else {
+                format!("{}:{}", ip_address, port)
+            };
+            vec![ListenerConfig {
+                address,
+                tls: use_tls,
+                behind_proxy: chorus_is_behind_a_proxy,
+                proxy_protocol: false,
+                implicit: true,
+            }]
+        } else {
+            listeners
+                .into_iter()
+                .map(|l| ListenerConfig {
+                    address: l.address,
+                    tls: l.tls.unwrap_or(use_tls),
+                    behind_proxy: l.behind_proxy.unwrap_or(chorus_is_behind_a_proxy),
+                    proxy_protocol: l.proxy_protocol,
+                    implicit: false,
+                })
+                .collect()
+        };
+
+        // Proxies on Unix domain sockets are trusted, but others must be listed
+        if trusted_proxies.is_empty()
+            && listeners
+                .iter()
+                .any(|l| (l.behind_proxy || l.proxy_protocol) && l.unix_path().is_none())
+        {
+            return Err(ChorusError::General(
+                "trusted_proxies must be set to listen behind a proxy".to_owned(),
+            )
+            .into());
+        }
+
         let server_log_level =
             log::LevelFilter::from_str(&server_log_level).unwrap_or(log::LevelFilter::Info);
         let library_log_level =
@@ -160,8 +322,13 @@ impl FriendlyConfig {
             data_directory,
             ip_address,
             port,
+            handoff_socket,
+            handoff_drain_seconds,
+            listeners,
             hostname,
             chorus_is_behind_a_proxy,
+            trusted_proxies,
+            proxy_ip_header,
             base_url,
             use_tls,
             certchain_pem_path,
@@ -190,12 +357,27 @@ impl FriendlyConfig {
             client_log_level,
             enable_ip_blocking,
             minimum_ban_seconds,
+            ip_reputation_half_life_days,
+            ip_hash_key_rotation_days,
             timeout_seconds,
             max_connections_per_ip,
+            subnet_prefix_len_v4,
+            subnet_prefix_len_v6,
+            max_connections_per_subnet,
             throttling_bytes_per_second,
             throttling_burst,
+            websocket_compression,
+            websocket_compression_window_bits,
+            websocket_compression_min_bytes,
             blossom_directory,
             enable_negentropy,
+            metrics_listen_address,
+            public_health_endpoints,
+            metrics_event_kinds,
+            log_format,
+            log_file,
+            log_file_max_bytes,
+            log_file_keep,
         })
     }
 }
@@ -205,8 +387,13 @@ pub struct Config {
     pub data_directory: String,
     pub ip_address: String,
     pub port: u16,
+    pub handoff_socket: Option<String>,
+    pub handoff_drain_seconds: u64,
+    pub listeners: Vec<ListenerConfig>,
     pub hostname: Host,
     pub chorus_is_behind_a_proxy: bool,
+    pub trusted_proxies: Vec<IpCidr>,
+    pub proxy_ip_header: ProxyIpHeader,
     pub base_url: Option<String>,
     pub use_tls: bool,
     pub certchain_pem_path: String,
@@ -235,12 +422,27 @@ pub struct Config {
     pub client_log_level: log::LevelFilter,
     pub enable_ip_blocking: bool,
     pub minimum_ban_seconds: u64,
+    pub ip_reputation_half_life_days: u64,
+    pub ip_hash_key_rotation_days: u64,
     pub timeout_seconds: u64,
     pub max_connections_per_ip: usize,
+    pub subnet_prefix_len_v4: u8,
+    pub subnet_prefix_len_v6: u8,
+    pub max_connections_per_subnet: usize,
     pub throttling_bytes_per_second: usize,
     pub throttling_burst: usize,
+    pub websocket_compression: bool,
+    pub websocket_compression_window_bits: u8,
+    pub websocket_compression_min_bytes: usize,
     pub blossom_directory: Option<String>,
     pub enable_negentropy: bool,
+    pub metrics_listen_address: Option<String>,
+    pub public_health_endpoints: bool,
+    pub metrics_event_kinds: Vec<u16>,
+    pub log_format: String,
+    pub log_file: Option<String>,
+    pub log_file_max_bytes: u64,
+    pub log_file_keep: usize,
 }
 
 impl Default for Config {
@@ -253,6 +455,41 @@ impl Default for Config {
 }
 
 impl Config {
+    /// Put back our values of settings that only take effect after a restart, so the
+    /// running config keeps describing what is in use. Returns the names of those that
+    /// differed in `new`.
+    pub fn keep_restart_required(&self, new: &mut Config) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = Vec::new();
+        macro_rules! check {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != new.$field {
+                        names.push(stringify!($field));
+                        new.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+        check!(
+            data_directory,
+            ip_address,
+            port,
+            handoff_socket,
+            listeners,
+            use_tls,
+            blossom_directory,
+            metrics_listen_address,
+            server_log_level,
+            library_log_level,
+            client_log_level,
+            log_format,
+            log_file,
+            log_file_max_bytes,
+            log_file_keep
+        );
+        names
+    }
+
     /// Get the URI for our server matching the inner Uri, overridden with either
     /// our base_url parts or our hostname/port.
     pub fn uri_parts(&self, inner: Uri, http: bool) -> Result<http::uri::Parts, Error> {
@@ -279,3 +516,79 @@ impl Config {
         Ok(uri_parts)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str::<FriendlyConfig>(toml)
+            .unwrap()
+            .into_config()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_listeners() {
+        // Without listeners, listen on ip_address and port
+        let config = parse("ip_address = \"::1\"\nport = 8080\nuse_tls = true\n");
+        assert_eq!(
+            config.listeners,
+            vec![ListenerConfig {
+                address: "[::1]:8080".to_owned(),
+                tls: true,
+                behind_proxy: false,
+                proxy_protocol: false,
+                implicit: true,
+            }]
+        );
+
+        // Listeners default to use_tls and chorus_is_behind_a_proxy
+        let config = parse(
+            r#"
+            use_tls = true
+            chorus_is_behind_a_proxy = true
+            trusted_proxies = [ "127.0.0.1" ]
+
+            [[listeners]]
+            address = "0.0.0.0:443"
+
+            [[listeners]]
+            address = "unix:/run/chorus.sock"
+            tls = false
+            proxy_protocol = true
+            "#,
+        );
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[0].address, "0.0.0.0:443");
+        assert!(config.listeners[0].tls);
+        assert!(config.listeners[0].behind_proxy);
+        assert!(!config.listeners[0].implicit);
+        assert_eq!(config.listeners[0].unix_path(), None);
+        assert_eq!(config.listeners[1].unix_path(), Some("/run/chorus.sock"));
+        assert!(!config.listeners[1].tls);
+        assert!(config.listeners[1].behind_proxy);
+        assert!(config.listeners[1].proxy_protocol);
+    }
+
+    #[test]
+    fn test_trusted_proxies_required() {
+        let load = |toml: &str| {
+            toml::from_str::<FriendlyConfig>(toml)
+                .unwrap()
+                .into_config()
+        };
+
+        // Behind a proxy on TCP, nobody would be trusted
+        assert!(load("chorus_is_behind_a_proxy = true\n").is_err());
+        assert!(load("[[listeners]]\naddress = \"0.0.0.0:443\"\nproxy_protocol = true\n").is_err());
+        assert!(load("chorus_is_behind_a_proxy = true\ntrusted_proxies = [ \"::1\" ]\n").is_ok());
+
+        // Proxies on Unix domain sockets are trusted anyway
+        let config = load(
+            "chorus_is_behind_a_proxy = true\n[[listeners]]\naddress = \"unix:/run/chorus.sock\"\n",
+        )
+        .unwrap();
+        assert!(config.trusted_proxies.is_empty());
+    }
+}
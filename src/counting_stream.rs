@@ -1,11 +1,35 @@
 use crate::globals::GLOBALS;
 use std::pin::Pin;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 #[derive(Debug)]
-pub struct CountingStream<S>(pub S);
+pub struct CountingStream<S> {
+    stream: S,
+    inbound: &'static AtomicU64,
+    outbound: &'static AtomicU64,
+}
+
+impl<S> CountingStream<S> {
+    /// Count the bytes on the wire (compressed, and encrypted if using TLS)
+    pub fn new(stream: S) -> CountingStream<S> {
+        CountingStream {
+            stream,
+            inbound: &GLOBALS.bytes_inbound,
+            outbound: &GLOBALS.bytes_outbound,
+        }
+    }
+
+    /// Count websocket bytes as they are before compression
+    pub fn uncompressed(stream: S) -> CountingStream<S> {
+        CountingStream {
+            stream,
+            inbound: &GLOBALS.bytes_inbound_uncompressed,
+            outbound: &GLOBALS.bytes_outbound_uncompressed,
+        }
+    }
+}
 
 impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
     fn poll_read(
@@ -14,14 +38,13 @@ impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         // Count bytes for statistics
+        let this = self.get_mut();
         let pre = buf.filled().len();
-        let result = Pin::new(&mut self.get_mut().0).poll_read(cx, buf);
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
         let post = buf.filled().len();
         let count = post - pre;
         if count > 0 {
-            let _ = GLOBALS
-                .bytes_inbound
-                .fetch_add(count as u64, Ordering::SeqCst);
+            let _ = this.inbound.fetch_add(count as u64, Ordering::SeqCst);
         }
         result
     }
@@ -34,20 +57,19 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
         buf: &[u8],
     ) -> Poll<Result<usize, std::io::Error>> {
         // Count bytes for statistics
-        let _ = GLOBALS
-            .bytes_outbound
-            .fetch_add(buf.len() as u64, Ordering::SeqCst);
-        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        let this = self.get_mut();
+        let _ = this.outbound.fetch_add(buf.len() as u64, Ordering::SeqCst);
+        Pin::new(&mut this.stream).poll_write(cx, buf)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
     }
 
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
     }
 }
@@ -0,0 +1,627 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use hyper::header::HeaderMap;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Every message compressed with a sync flush ends in this, which is not sent
+const SYNC_TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+// How much compressed output we hold before making the writer wait
+const HIGH_WATER: usize = 64 * 1024;
+
+/// The parameters of a negotiated permessage-deflate extension (RFC 7692)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// The window size we compress with, as a power of two
+    pub window_bits: u8,
+
+    /// Whether we reset our compressor after each message
+    pub no_context_takeover: bool,
+
+    // Whether the client offered server_max_window_bits, which we must then answer
+    window_bits_offered: bool,
+}
+
+impl DeflateParams {
+    /// The Sec-WebSocket-Extensions header value accepting these parameters
+    pub fn response_header(&self) -> String {
+        let mut header = "permessage-deflate".to_owned();
+        if self.no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.window_bits < 15 || self.window_bits_offered {
+            header.push_str(&format!("; server_max_window_bits={}", self.window_bits));
+        }
+        header
+    }
+}
+
+/// Choose the first permessage-deflate offer in the Sec-WebSocket-Extensions headers
+/// that we can accept, compressing with a window of at most `window_bits`
+pub fn negotiate(headers: &HeaderMap, window_bits: u8) -> Option<DeflateParams> {
+    for value in headers.get_all("sec-websocket-extensions") {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for offer in value.split(',') {
+            let mut parts = offer.split(';').map(|p| p.trim()).filter(|p| !p.is_empty());
+            if parts.next() != Some("permessage-deflate") {
+                continue;
+            }
+            if let Some(params) = accept_offer(parts, window_bits) {
+                return Some(params);
+            }
+        }
+    }
+    None
+}
+
+// Accept an offer's parameters, or None if we cannot
+fn accept_offer<'a>(
+    params: impl Iterator<Item = &'a str>,
+    window_bits: u8,
+) -> Option<DeflateParams> {
+    let mut accepted = DeflateParams {
+        window_bits,
+        no_context_takeover: false,
+        window_bits_offered: false,
+    };
+    let mut seen: Vec<&str> = Vec::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+
+        match (name, value) {
+            ("server_no_context_takeover", None) => accepted.no_context_takeover = true,
+            // We keep our inflate context either way
+            ("client_no_context_takeover", None) => {}
+            ("server_max_window_bits", Some(value)) => {
+                // zlib cannot compress with a window of 8 bits
+                let bits: u8 = value.parse().ok().filter(|b| (9..=15).contains(b))?;
+                accepted.window_bits = accepted.window_bits.min(bits);
+                accepted.window_bits_offered = true;
+            }
+            // We inflate with the largest window, so accept any
+            ("client_max_window_bits", None) => {}
+            ("client_max_window_bits", Some(value)) => {
+                value.parse::<u8>().ok().filter(|b| (8..=15).contains(b))?;
+            }
+            _ => return None,
+        }
+    }
+    Some(accepted)
+}
+
+/// A websocket stream that compresses and decompresses messages with permessage-deflate,
+/// so that the websocket library above it only sees uncompressed frames. Without
+/// negotiated parameters it passes everything through untouched.
+pub struct DeflateStream<S> {
+    inner: S,
+    state: Option<Box<DeflateState>>,
+}
+
+impl<S> DeflateStream<S> {
+    /// Wrap a server-side websocket stream. Messages we send that are smaller than
+    /// `min_bytes` are not compressed. Messages we receive may inflate to at most
+    /// `max_message_size`.
+    pub fn new(
+        inner: S,
+        params: Option<DeflateParams>,
+        min_bytes: usize,
+        max_message_size: usize,
+    ) -> DeflateStream<S> {
+        let state = params.map(|params| {
+            Box::new(DeflateState {
+                params,
+                min_bytes,
+                max_message_size,
+                compress: Compress::new_with_window_bits(
+                    Compression::default(),
+                    false,
+                    params.window_bits,
+                ),
+                decompress: Decompress::new(false),
+                read_raw: Vec::new(),
+                read_ready: Vec::new(),
+                read_pos: 0,
+                read_message: None,
+                eof: false,
+                write_raw: Vec::new(),
+                write_ready: Vec::new(),
+                write_pos: 0,
+            })
+        });
+        DeflateStream { inner, state }
+    }
+}
+
+// A data message being received over several frames
+enum ReadMessage {
+    Plain,
+    Compressed { opcode: u8, payload: Vec<u8> },
+}
+
+struct DeflateState {
+    params: DeflateParams,
+    min_bytes: usize,
+    max_message_size: usize,
+    compress: Compress,
+    decompress: Decompress,
+
+    // Bytes read that do not yet make a whole frame, and frames ready to be read
+    read_raw: Vec<u8>,
+    read_ready: Vec<u8>,
+    read_pos: usize,
+    read_message: Option<ReadMessage>,
+    eof: bool,
+
+    // Bytes written that do not yet make a whole frame, and frames ready to be sent
+    write_raw: Vec<u8>,
+    write_ready: Vec<u8>,
+    write_pos: usize,
+}
+
+impl DeflateState {
+    // Move the whole frames received into read_ready, inflating compressed messages
+    fn process_incoming(&mut self) -> io::Result<()> {
+        let mut raw = std::mem::take(&mut self.read_raw);
+        let mut pos = 0;
+        while let Some(header) = parse_header(&raw[pos..], self.max_message_size)? {
+            let frame_len = header.header_len + header.payload_len;
+            if raw.len() - pos < frame_len {
+                break;
+            }
+            let frame = &raw[pos..pos + frame_len];
+            let payload = &frame[header.header_len..];
+            pos += frame_len;
+
+            match (header.opcode, &mut self.read_message) {
+                // A compressed text or binary message starts
+                (0x1 | 0x2, _) if header.rsv1 => {
+                    let mut payload = payload.to_vec();
+                    unmask(&mut payload, header.mask);
+                    if header.fin {
+                        let inflated = self.inflate(&payload)?;
+                        write_frame(&mut self.read_ready, 0x80 | header.opcode, true, &inflated);
+                    } else {
+                        self.read_message = Some(ReadMessage::Compressed {
+                            opcode: header.opcode,
+                            payload,
+                        });
+                    }
+                }
+
+                // A compressed message continues
+                (
+                    0x0,
+                    Some(ReadMessage::Compressed {
+                        opcode,
+                        payload: so_far,
+                    }),
+                ) => {
+                    let start = so_far.len();
+                    so_far.extend_from_slice(payload);
+                    unmask(&mut so_far[start..], header.mask);
+                    if so_far.len() > self.max_message_size {
+                        return Err(too_big());
+                    }
+                    if header.fin {
+                        let opcode = *opcode;
+                        let so_far = std::mem::take(so_far);
+                        self.read_message = None;
+                        let inflated = self.inflate(&so_far)?;
+                        write_frame(&mut self.read_ready, 0x80 | opcode, true, &inflated);
+                    }
+                }
+
+                // Uncompressed data and control frames pass through as they are
+                _ => {
+                    if header.opcode < 0x8 {
+                        self.read_message = (!header.fin).then_some(ReadMessage::Plain);
+                    }
+                    self.read_ready.extend_from_slice(frame);
+                }
+            }
+        }
+        // Keep the partial frame (if any) in the same buffer
+        if pos > 0 {
+            raw.drain(..pos);
+        }
+        self.read_raw = raw;
+        Ok(())
+    }
+
+    // Move the whole frames written into write_ready, compressing data messages
+    fn process_outgoing(&mut self) -> io::Result<()> {
+        let mut raw = std::mem::take(&mut self.write_raw);
+        let mut pos = 0;
+        while let Some(header) = parse_header(&raw[pos..], usize::MAX)? {
+            let frame_len = header.header_len + header.payload_len;
+            if raw.len() - pos < frame_len {
+                break;
+            }
+            let frame = &raw[pos..pos + frame_len];
+            let payload = &frame[header.header_len..];
+            pos += frame_len;
+
+            // Only whole text and binary messages are compressed
+            let compressible = matches!(header.opcode, 0x1 | 0x2)
+                && header.fin
+                && !header.rsv1
+                && header.mask.is_none()
+                && payload.len() >= self.min_bytes;
+            if compressible {
+                let compressed = self.deflate(payload)?;
+                write_frame(
+                    &mut self.write_ready,
+                    0x80 | 0x40 | header.opcode,
+                    false,
+                    &compressed,
+                );
+            } else {
+                self.write_ready.extend_from_slice(frame);
+            }
+        }
+        // Keep the partial frame (if any) in the same buffer
+        if pos > 0 {
+            raw.drain(..pos);
+        }
+        self.write_raw = raw;
+        Ok(())
+    }
+
+    fn inflate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut input = data.to_vec();
+        input.extend_from_slice(&SYNC_TAIL);
+        let mut output: Vec<u8> = Vec::with_capacity(input.len() * 4);
+        let mut consumed = 0;
+        loop {
+            let (in_before, out_before) = (self.decompress.total_in(), output.len());
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            consumed += (self.decompress.total_in() - in_before) as usize;
+            if output.len() > self.max_message_size {
+                return Err(too_big());
+            }
+            if status == Status::StreamEnd {
+                // The client ended its stream, and will start a new one
+                self.decompress.reset(false);
+                break;
+            }
+            let done = consumed == input.len() && output.len() < output.capacity();
+            let stuck = self.decompress.total_in() == in_before && output.len() == out_before;
+            if done || (stuck && output.len() < output.capacity()) {
+                break;
+            }
+            output.reserve(output.capacity().max(1024));
+        }
+        Ok(output)
+    }
+
+    fn deflate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output: Vec<u8> = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            let in_before = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed += (self.compress.total_in() - in_before) as usize;
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity().max(1024));
+        }
+        if output.ends_with(&SYNC_TAIL) {
+            output.truncate(output.len() - SYNC_TAIL.len());
+        }
+        if self.params.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(output)
+    }
+
+    // Send what is ready to be sent
+    fn poll_send<S: AsyncWrite + Unpin>(
+        &mut self,
+        inner: &mut S,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_ready.len() {
+            let n =
+                ready!(Pin::new(&mut *inner).poll_write(cx, &self.write_ready[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_ready.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// A websocket frame header
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+// Parse the frame header at the start of buf, or None if it is not all there yet
+fn parse_header(buf: &[u8], max_payload_len: usize) -> io::Result<Option<FrameHeader>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (payload_len, mut header_len) = match buf[1] & 0x7F {
+        126 if buf.len() < 4 => return Ok(None),
+        126 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() < 10 => return Ok(None),
+        127 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        n => (n as u64, 2),
+    };
+    let mask = if buf[1] & 0x80 != 0 {
+        if buf.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let mask: [u8; 4] = buf[header_len..header_len + 4].try_into().unwrap();
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    if payload_len > max_payload_len as u64 {
+        return Err(too_big());
+    }
+    Ok(Some(FrameHeader {
+        fin: buf[0] & 0x80 != 0,
+        rsv1: buf[0] & 0x40 != 0,
+        opcode: buf[0] & 0x0F,
+        mask,
+        header_len,
+        payload_len: payload_len as usize,
+    }))
+}
+
+// Write a frame. A masked frame gets a zero mask, which leaves the payload as is.
+fn write_frame(output: &mut Vec<u8>, first_byte: u8, masked: bool, payload: &[u8]) {
+    let mask_bit = if masked { 0x80 } else { 0 };
+    output.push(first_byte);
+    if payload.len() < 126 {
+        output.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        output.push(mask_bit | 126);
+        output.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        output.push(mask_bit | 127);
+        output.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    if masked {
+        output.extend_from_slice(&[0; 4]);
+    }
+    output.extend_from_slice(payload);
+}
+
+fn unmask(payload: &mut [u8], mask: Option<[u8; 4]>) {
+    if let Some(mask) = mask {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "websocket message too large")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let DeflateStream { inner, state } = self.get_mut();
+        let Some(state) = state else {
+            return Pin::new(inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if state.read_pos < state.read_ready.len() {
+                let n = buf.remaining().min(state.read_ready.len() - state.read_pos);
+                buf.put_slice(&state.read_ready[state.read_pos..state.read_pos + n]);
+                state.read_pos += n;
+                if state.read_pos == state.read_ready.len() {
+                    state.read_ready.clear();
+                    state.read_pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            if state.eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut *inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                // Pass on any partial frame, for the websocket library to complain about
+                state.eof = true;
+                let rest = std::mem::take(&mut state.read_raw);
+                state.read_ready.extend_from_slice(&rest);
+                continue;
+            }
+            state.read_raw.extend_from_slice(chunk_buf.filled());
+            state.process_incoming()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let DeflateStream { inner, state } = self.get_mut();
+        let Some(state) = state else {
+            return Pin::new(inner).poll_write(cx, buf);
+        };
+
+        if state.write_ready.len() - state.write_pos >= HIGH_WATER {
+            ready!(state.poll_send(inner, cx))?;
+        }
+        state.write_raw.extend_from_slice(buf);
+        state.process_outgoing()?;
+
+        // Start sending, but we have taken the bytes either way
+        if let Poll::Ready(Err(e)) = state.poll_send(inner, cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let DeflateStream { inner, state } = self.get_mut();
+        if let Some(state) = state {
+            ready!(state.poll_send(inner, cx))?;
+        }
+        Pin::new(inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let DeflateStream { inner, state } = self.get_mut();
+        if let Some(state) = state {
+            ready!(state.poll_send(inner, cx))?;
+        }
+        Pin::new(inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn headers(value: &'static str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        map.insert("sec-websocket-extensions", value.parse().unwrap());
+        map
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let params = negotiate(&headers("permessage-deflate; client_max_window_bits"), 15).unwrap();
+        assert_eq!(params.response_header(), "permessage-deflate");
+
+        let params = negotiate(&headers("permessage-deflate"), 12).unwrap();
+        assert_eq!(
+            params.response_header(),
+            "permessage-deflate; server_max_window_bits=12"
+        );
+
+        // The first offer we can accept wins
+        let params = negotiate(
+            &headers(
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate; server_max_window_bits=10; server_no_context_takeover",
+            ),
+            15,
+        )
+        .unwrap();
+        assert_eq!(params.window_bits, 10);
+        assert!(params.no_context_takeover);
+        assert_eq!(
+            params.response_header(),
+            "permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+        );
+
+        assert!(negotiate(&headers("permessage-deflate; unknown"), 15).is_none());
+        assert!(negotiate(&headers("x-webkit-deflate-frame"), 15).is_none());
+        assert!(negotiate(&HeaderMap::new(), 15).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deflate_stream() {
+        let params = negotiate(&headers("permessage-deflate"), 15);
+        let message = "[\"EVENT\",\"sub\",{}]".repeat(20);
+
+        // Compress as a client would, with our own compressor
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client_side = DeflateStream::new(client, params, 0, 1024 * 1024);
+        let mut server_side = DeflateStream::new(server, params, 16, 1024 * 1024);
+
+        // The server writes an uncompressed frame, which goes out compressed
+        let mut frame: Vec<u8> = Vec::new();
+        write_frame(&mut frame, 0x81, false, message.as_bytes());
+        server_side.write_all(&frame).await.unwrap();
+        server_side.flush().await.unwrap();
+
+        let mut wire = vec![0u8; 4096];
+        let n = client_side.inner.read(&mut wire).await.unwrap();
+        let header = parse_header(&wire[..n], usize::MAX).unwrap().unwrap();
+        assert!(header.rsv1);
+        assert!(header.payload_len < message.len() / 4);
+
+        // The client sends it back masked, fragmented and compressed, with a ping between
+        let compressed = wire[header.header_len..n].to_vec();
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mask = [1, 2, 3, 4];
+        let mut input: Vec<u8> = Vec::new();
+        for (first_byte, part) in [(0x41, first), (0x80, second)] {
+            let mut part = part.to_vec();
+            unmask(&mut part, Some(mask));
+            input.extend_from_slice(&[first_byte, 0x80 | part.len() as u8]);
+            input.extend_from_slice(&mask);
+            input.extend_from_slice(&part);
+            if first_byte == 0x41 {
+                input.extend_from_slice(&[0x89, 0x80, 0, 0, 0, 0]);
+            }
+        }
+        client_side.inner.write_all(&input).await.unwrap();
+        drop(client_side);
+
+        let mut output: Vec<u8> = Vec::new();
+        server_side.read_to_end(&mut output).await.unwrap();
+
+        // The ping passes through, then the message, inflated and unfragmented
+        assert_eq!(&output[..6], &[0x89, 0x80, 0, 0, 0, 0]);
+        let header = parse_header(&output[6..], usize::MAX).unwrap().unwrap();
+        assert!(header.fin && !header.rsv1);
+        assert_eq!(header.opcode, 0x1);
+        assert_eq!(&output[6 + header.header_len..], message.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_partial_frames() {
+        let params = negotiate(&headers("permessage-deflate"), 15);
+        let (mut client, server) = tokio::io::duplex(3);
+        let mut server_side = DeflateStream::new(server, params, 16, 1024 * 1024);
+
+        // Frames arrive a few bytes at a time, and pass through whole
+        let mut input: Vec<u8> = Vec::new();
+        for message in ["first", "second message"] {
+            input.extend_from_slice(&[0x81, 0x80 | message.len() as u8, 0, 0, 0, 0]);
+            input.extend_from_slice(message.as_bytes());
+        }
+        let expected = input.clone();
+        tokio::spawn(async move {
+            client.write_all(&input).await.unwrap();
+        });
+
+        let mut output: Vec<u8> = Vec::new();
+        server_side.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, expected);
+    }
+}
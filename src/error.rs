@@ -89,6 +89,9 @@ pub enum ChorusError {
     // Infallible
     Infallible,
 
+    // Invalid IP address or CIDR network
+    InvalidCidr(String),
+
     // Invalid URI
     InvalidUri(hyper::http::uri::InvalidUri),
 
@@ -98,6 +101,9 @@ pub enum ChorusError {
     // I/O
     Io(std::io::Error),
 
+    // Kicked by a moderator
+    Kicked,
+
     // Management Authorization failure
     ManagementAuthFailure(String),
 
@@ -122,6 +128,9 @@ pub enum ChorusError {
     // Protected Event
     ProtectedEvent,
 
+    // Invalid PROXY protocol header
+    ProxyProtocol(&'static str),
+
     // Pocket Db Error
     PocketDb(pocket_db::Error),
 
@@ -207,9 +216,11 @@ impl std::fmt::Display for ChorusError {
             ChorusError::Http(e) => write!(f, "{e}"),
             ChorusError::Hyper(e) => write!(f, "{e}"),
             ChorusError::Infallible => panic!("INFALLIBLE"),
+            ChorusError::InvalidCidr(s) => write!(f, "Invalid IP address or network: {s}"),
             ChorusError::InvalidUri(e) => write!(f, "{e}"),
             ChorusError::InvalidUriParts(e) => write!(f, "{e}"),
             ChorusError::Io(e) => write!(f, "{e}"),
+            ChorusError::Kicked => write!(f, "Kicked by a moderator"),
             ChorusError::ManagementAuthFailure(s) => write!(f, "Authorization failure: {s}"),
             ChorusError::MissingTable(t) => write!(f, "Missing table: {t}"),
             ChorusError::Negentropy(e) => write!(f, "Negentropy: {e}"),
@@ -224,7 +235,8 @@ impl std::fmt::Display for ChorusError {
             ChorusError::PocketType(e) => write!(f, "{e}"),
             ChorusError::RateLimitExceeded => write!(f, "Rate limit exceeded"),
             ChorusError::ProtectedEvent => write!(f, "Protected event"),
-            ChorusError::RealIpHeaderMissing => write!(f, "X-Real-Ip header is missing"),
+            ChorusError::ProxyProtocol(s) => write!(f, "Invalid PROXY protocol header: {s}"),
+            ChorusError::RealIpHeaderMissing => write!(f, "Client IP header is missing"),
             ChorusError::Restricted => write!(f, "Restricted"),
             ChorusError::Rustls(e) => write!(f, "{e}"),
             ChorusError::Scraper => write!(f, "Filter is underspecified. Scrapers are not allowed"),
@@ -307,9 +319,11 @@ impl ChorusError {
             ChorusError::Http(_) => 0.0,
             ChorusError::Hyper(_) => 0.0,
             ChorusError::Infallible => panic!("INFALLIBLE"),
+            ChorusError::InvalidCidr(_) => 0.0,
             ChorusError::InvalidUri(_) => 0.0,
             ChorusError::InvalidUriParts(_) => 0.0,
             ChorusError::Io(_) => 0.0,
+            ChorusError::Kicked => 0.0,
             ChorusError::ManagementAuthFailure(_) => 0.0,
             ChorusError::MissingTable(_) => 0.0,
             ChorusError::Negentropy(_) => 0.1,
@@ -322,6 +336,7 @@ impl ChorusError {
             ChorusError::PocketType(_) => 0.25,
             ChorusError::RateLimitExceeded => 1.0,
             ChorusError::ProtectedEvent => 0.35,
+            ChorusError::ProxyProtocol(_) => 0.0,
             ChorusError::RealIpHeaderMissing => 0.0,
             ChorusError::Restricted => 0.1,
             ChorusError::Rustls(_) => 0.0,
@@ -339,6 +354,67 @@ impl ChorusError {
             ChorusError::WebsocketProtocol(_) => 0.1,
         }
     }
+
+    /// The name of the variant, for counting errors by kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChorusError::AuthFailure(_) => "AuthFailure",
+            ChorusError::AuthRequired => "AuthRequired",
+            ChorusError::BadRequest(_) => "BadRequest",
+            ChorusError::BadRealIpHeader(_) => "BadRealIpHeader",
+            ChorusError::BadRealIpHeaderCharacters => "BadRealIpHeaderCharacters",
+            ChorusError::BannedEvent => "BannedEvent",
+            ChorusError::BannedUser => "BannedUser",
+            ChorusError::Base64Decode(_) => "Base64Decode",
+            ChorusError::BlockedIp => "BlockedIp",
+            ChorusError::BlossomAuthFailure(_) => "BlossomAuthFailure",
+            ChorusError::ChannelRecv(_) => "ChannelRecv",
+            ChorusError::ChannelSend(_) => "ChannelSend",
+            ChorusError::Config(_) => "Config",
+            ChorusError::Crypto(_) => "Crypto",
+            ChorusError::ErrorClose => "ErrorClose",
+            ChorusError::EventIsInvalid(_) => "EventIsInvalid",
+            ChorusError::FromHex(_) => "FromHex",
+            ChorusError::FromUtf8(_) => "FromUtf8",
+            ChorusError::General(_) => "General",
+            ChorusError::Http(_) => "Http",
+            ChorusError::Hyper(_) => "Hyper",
+            ChorusError::Infallible => "Infallible",
+            ChorusError::InvalidCidr(_) => "InvalidCidr",
+            ChorusError::InvalidUri(_) => "InvalidUri",
+            ChorusError::InvalidUriParts(_) => "InvalidUriParts",
+            ChorusError::Io(_) => "Io",
+            ChorusError::Kicked => "Kicked",
+            ChorusError::ManagementAuthFailure(_) => "ManagementAuthFailure",
+            ChorusError::MissingTable(_) => "MissingTable",
+            ChorusError::Negentropy(_) => "Negentropy",
+            ChorusError::NonAsciiHttpHeaderValue(_) => "NonAsciiHttpHeaderValue",
+            ChorusError::NoPrivateKey => "NoPrivateKey",
+            ChorusError::NotImplemented => "NotImplemented",
+            ChorusError::NoSuchSubscription => "NoSuchSubscription",
+            ChorusError::PocketDb(_) => "PocketDb",
+            ChorusError::PocketDbHeed(_) => "PocketDbHeed",
+            ChorusError::PocketType(_) => "PocketType",
+            ChorusError::RateLimitExceeded => "RateLimitExceeded",
+            ChorusError::ProtectedEvent => "ProtectedEvent",
+            ChorusError::ProxyProtocol(_) => "ProxyProtocol",
+            ChorusError::RealIpHeaderMissing => "RealIpHeaderMissing",
+            ChorusError::Restricted => "Restricted",
+            ChorusError::Rustls(_) => "Rustls",
+            ChorusError::Scraper => "Scraper",
+            ChorusError::SerdeJson(_) => "SerdeJson",
+            ChorusError::ShuttingDown => "ShuttingDown",
+            ChorusError::SignalNotBlossom => "SignalNotBlossom",
+            ChorusError::Speedy(_) => "Speedy",
+            ChorusError::TimedOut => "TimedOut",
+            ChorusError::TooManySubscriptions => "TooManySubscriptions",
+            ChorusError::Tungstenite(_) => "Tungstenite",
+            ChorusError::UrlParse(_) => "UrlParse",
+            ChorusError::Utf8(_) => "Utf8",
+            ChorusError::Utf8Error => "Utf8Error",
+            ChorusError::WebsocketProtocol(_) => "WebsocketProtocol",
+        }
+    }
 }
 
 // Note: we impl Into because our typical pattern is ChorusError::Variant.into()
@@ -0,0 +1,432 @@
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
+use crate::ip::{HashedIp, IpBlock};
+use crate::roles::{Permission, PermissionSet, BUILTIN_ROLES};
+use crate::Approval;
+use pocket_db::heed::types::Bytes;
+use pocket_db::heed::Database;
+use pocket_types::{Id, Pubkey, Time};
+use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
+use std::str::FromStr;
+
+/// The version of the moderation state document that we write and accept
+pub const STATE_VERSION: u32 = 1;
+
+// The tables holding moderation state. Replacing imports clear all of them.
+const STATE_TABLES: [&str; 7] = [
+    "admins",
+    "approved-events",
+    "approved-pubkeys",
+    "blocked-ips",
+    "roles",
+    "user-roles",
+    "users",
+];
+
+/// All moderation state (approvals, users, admins, roles and IP blocks) as a
+/// portable document. Root admins from the config file are not included.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModerationState {
+    pub version: u32,
+    #[serde(default)]
+    pub exported_at: u64,
+    #[serde(default)]
+    pub event_approvals: Vec<ApprovalEntry>,
+    #[serde(default)]
+    pub pubkey_approvals: Vec<ApprovalEntry>,
+    #[serde(default)]
+    pub users: Vec<UserEntry>,
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<RoleEntry>,
+    #[serde(default)]
+    pub user_roles: Vec<UserRoleEntry>,
+    #[serde(default)]
+    pub ip_blocks: Vec<IpBlockEntry>,
+    /// Fingerprints of the IP hash keys the IP blocks were hashed with
+    #[serde(default)]
+    pub ip_hash_keys: Vec<String>,
+}
+
+/// An event or pubkey approval. The key is the id or pubkey in hex.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalEntry {
+    pub key: String,
+    pub approved: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEntry {
+    pub pubkey: String,
+    #[serde(default)]
+    pub moderator: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoleEntry {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRoleEntry {
+    pub pubkey: String,
+    pub role: String,
+}
+
+/// An IP block. The ip is the hashed IP (or network) as displayed elsewhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpBlockEntry {
+    pub ip: String,
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_len: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_created_at: Option<u64>,
+}
+
+/// How an import combines with the existing moderation state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Keep existing entries, overwriting those that are also in the document
+    Merge,
+
+    /// Remove all existing entries first
+    Replace,
+}
+
+impl FromStr for ImportMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ImportMode, Error> {
+        match s {
+            "merge" => Ok(ImportMode::Merge),
+            "replace" => Ok(ImportMode::Replace),
+            _ => Err(ChorusError::BadRequest("Import mode must be merge or replace").into()),
+        }
+    }
+}
+
+fn state_table(name: &'static str) -> Result<Database<Bytes, Bytes>, Error> {
+    GLOBALS
+        .store
+        .get()
+        .unwrap()
+        .extra_table(name)
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(name)))
+}
+
+fn approval_entry(key: &[u8], val: &[u8]) -> ApprovalEntry {
+    let approval = Approval::from_bytes(val);
+    ApprovalEntry {
+        key: hex::encode(key),
+        approved: approval.approved,
+        created_at: approval.created_at,
+        reason: approval.reason,
+    }
+}
+
+/// Export all moderation state, read in a single transaction
+pub fn export_state() -> Result<ModerationState, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let txn = store.read_txn()?;
+    let mut state = ModerationState {
+        version: STATE_VERSION,
+        exported_at: Time::now().as_u64(),
+        ip_hash_keys: crate::ip::ip_hash_keys()
+            .iter()
+            .map(|k| k.fingerprint())
+            .collect(),
+        ..Default::default()
+    };
+
+    for i in state_table("approved-events")?.iter(&txn)? {
+        let (key, val) = i?;
+        state.event_approvals.push(approval_entry(key, val));
+    }
+    for i in state_table("approved-pubkeys")?.iter(&txn)? {
+        let (key, val) = i?;
+        state.pubkey_approvals.push(approval_entry(key, val));
+    }
+    for i in state_table("users")?.iter(&txn)? {
+        let (key, val) = i?;
+        state.users.push(UserEntry {
+            pubkey: hex::encode(key),
+            moderator: !val.is_empty() && val[0] != 0,
+        });
+    }
+    for i in state_table("admins")?.iter(&txn)? {
+        let (key, _val) = i?;
+        state.admins.push(hex::encode(key));
+    }
+    for i in state_table("roles")?.iter(&txn)? {
+        let (key, val) = i?;
+        let permissions = PermissionSet::from_bits(val.first().copied().unwrap_or(0));
+        state.roles.push(RoleEntry {
+            name: String::from_utf8_lossy(key).into_owned(),
+            permissions: permissions
+                .permissions()
+                .iter()
+                .map(|p| p.name().to_owned())
+                .collect(),
+        });
+    }
+    for i in state_table("user-roles")?.iter(&txn)? {
+        let (key, _val) = i?;
+        state.user_roles.push(UserRoleEntry {
+            pubkey: hex::encode(&key[0..32]),
+            role: String::from_utf8_lossy(&key[32..]).into_owned(),
+        });
+    }
+    for i in state_table("blocked-ips")?.iter(&txn)? {
+        let (key, val) = i?;
+        let block = IpBlock::read_from_buffer(val)?;
+        state.ip_blocks.push(IpBlockEntry {
+            ip: HashedIp::from_bytes(key).to_string(),
+            reason: block.reason,
+            created_at: block.created_at,
+            prefix_len: block.prefix_len,
+            key_created_at: block.key_created_at,
+        });
+    }
+
+    Ok(state)
+}
+
+fn read_id(hex: &str) -> Result<Id, Error> {
+    Id::read_hex(hex.as_bytes()).map_err(|_| ChorusError::BadRequest("Invalid id").into())
+}
+
+fn read_pubkey(hex: &str) -> Result<Pubkey, Error> {
+    Pubkey::read_hex(hex.as_bytes()).map_err(|_| ChorusError::BadRequest("Invalid pubkey").into())
+}
+
+/// Import moderation state in a single transaction. The whole document is checked
+/// before anything is written. Returns the number of entries written.
+pub fn import_state(state: &ModerationState, mode: ImportMode) -> Result<usize, Error> {
+    if state.version != STATE_VERSION {
+        return Err(ChorusError::BadRequest("Unsupported moderation state version").into());
+    }
+
+    // Convert everything to (table, key, value) records first
+    let mut records: Vec<(&'static str, Vec<u8>, Vec<u8>)> = Vec::new();
+    for entry in state.event_approvals.iter() {
+        let id = read_id(&entry.key)?;
+        records.push((
+            "approved-events",
+            id.as_slice().to_owned(),
+            entry_bytes(entry),
+        ));
+    }
+    for entry in state.pubkey_approvals.iter() {
+        let pubkey = read_pubkey(&entry.key)?;
+        records.push((
+            "approved-pubkeys",
+            pubkey.as_slice().to_owned(),
+            entry_bytes(entry),
+        ));
+    }
+    for entry in state.users.iter() {
+        let pubkey = read_pubkey(&entry.pubkey)?;
+        records.push((
+            "users",
+            pubkey.as_slice().to_owned(),
+            vec![entry.moderator as u8],
+        ));
+    }
+    for admin in state.admins.iter() {
+        let pubkey = read_pubkey(admin)?;
+        records.push(("admins", pubkey.as_slice().to_owned(), vec![]));
+    }
+    for entry in state.roles.iter() {
+        if entry.name.is_empty() || BUILTIN_ROLES.contains(&&*entry.name) {
+            return Err(ChorusError::BadRequest("That role name cannot be defined").into());
+        }
+        let permissions: PermissionSet = entry
+            .permissions
+            .iter()
+            .map(|p| p.parse::<Permission>())
+            .collect::<Result<_, Error>>()?;
+        records.push((
+            "roles",
+            entry.name.as_bytes().to_owned(),
+            vec![permissions.bits()],
+        ));
+    }
+    // Roles granted must be defined in the document (or already, when merging)
+    let existing_roles = match mode {
+        ImportMode::Merge => crate::dump_roles()?,
+        ImportMode::Replace => vec![],
+    };
+    for entry in state.user_roles.iter() {
+        if !state.roles.iter().any(|r| r.name == entry.role)
+            && !existing_roles.iter().any(|(name, _)| *name == entry.role)
+        {
+            return Err(ChorusError::BadRequest("A user role references an undefined role").into());
+        }
+        let pubkey = read_pubkey(&entry.pubkey)?;
+        let mut key: Vec<u8> = pubkey.as_slice().to_owned();
+        key.extend(entry.role.as_bytes());
+        records.push(("user-roles", key, vec![]));
+    }
+    // Hashed IPs only match on a relay with the IP hash keys they were hashed with
+    if !state.ip_blocks.is_empty() && !state.ip_hash_keys.is_empty() {
+        let ours: Vec<String> = crate::ip::ip_hash_keys()
+            .iter()
+            .map(|k| k.fingerprint())
+            .collect();
+        let missing = state
+            .ip_hash_keys
+            .iter()
+            .filter(|f| !ours.contains(f))
+            .count();
+        if missing == state.ip_hash_keys.len() {
+            return Err(ChorusError::BadRequest(
+                "The IP blocks were hashed with IP hash keys this relay does not have",
+            )
+            .into());
+        } else if missing > 0 {
+            log::warn!(
+                target: "Server",
+                "Importing IP blocks: {missing} of their IP hash keys are not ours"
+            );
+        }
+    }
+    for entry in state.ip_blocks.iter() {
+        let ip = HashedIp::from_tag(&entry.ip).ok_or(Into::<Error>::into(
+            ChorusError::BadRequest("Invalid hashed IP"),
+        ))?;
+        let block = IpBlock {
+            reason: entry.reason.clone(),
+            created_at: entry.created_at,
+            prefix_len: entry.prefix_len,
+            key_created_at: entry.key_created_at,
+        };
+        records.push(("blocked-ips", ip.0.to_vec(), block.write_to_vec()?));
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let mut txn = store.write_txn()?;
+    if mode == ImportMode::Replace {
+        for name in STATE_TABLES {
+            state_table(name)?.clear(&mut txn)?;
+        }
+    }
+    for (name, key, val) in records.iter() {
+        state_table(name)?.put(&mut txn, key, val)?;
+    }
+    txn.commit()?;
+    crate::load_blocked_prefix_lens()?;
+    crate::permissions_changed();
+
+    Ok(records.len())
+}
+
+fn entry_bytes(entry: &ApprovalEntry) -> Vec<u8> {
+    Approval {
+        approved: entry.approved,
+        created_at: entry.created_at,
+        reason: entry.reason.clone(),
+    }
+    .to_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn state() -> ModerationState {
+        let alice = "a".repeat(64);
+        let bob = "b".repeat(64);
+        ModerationState {
+            version: STATE_VERSION,
+            event_approvals: vec![ApprovalEntry {
+                key: "c".repeat(64),
+                approved: false,
+                created_at: Some(1700000000),
+                reason: Some("spam".to_owned()),
+            }],
+            pubkey_approvals: vec![ApprovalEntry {
+                key: bob.clone(),
+                approved: true,
+                created_at: None,
+                reason: None,
+            }],
+            users: vec![UserEntry {
+                pubkey: alice.clone(),
+                moderator: true,
+            }],
+            admins: vec![bob.clone()],
+            roles: vec![RoleEntry {
+                name: "reporter".to_owned(),
+                permissions: vec!["can-ban".to_owned()],
+            }],
+            user_roles: vec![UserRoleEntry {
+                pubkey: alice,
+                role: "reporter".to_owned(),
+            }],
+            ip_blocks: vec![IpBlockEntry {
+                ip: HashedIp::new("10.0.0.1".parse().unwrap()).to_string(),
+                reason: "abuse".to_owned(),
+                created_at: 1700000000,
+                prefix_len: None,
+                key_created_at: Some(crate::ip::ip_hash_keys()[0].created_at),
+            }],
+            ip_hash_keys: crate::ip::ip_hash_keys()
+                .iter()
+                .map(|k| k.fingerprint())
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let _guard = crate::test::setup_test_store();
+
+        assert_eq!(import_state(&state(), ImportMode::Replace).unwrap(), 7);
+        let exported = export_state().unwrap();
+        assert_eq!(import_state(&exported, ImportMode::Replace).unwrap(), 7);
+        let mut reexported = export_state().unwrap();
+        reexported.exported_at = exported.exported_at;
+        assert_eq!(
+            serde_json::to_value(&exported).unwrap(),
+            serde_json::to_value(&reexported).unwrap()
+        );
+        assert_eq!(exported.user_roles[0].role, "reporter");
+        assert_eq!(exported.ip_blocks[0].reason, "abuse");
+    }
+
+    #[test]
+    fn test_import_rejects_invalid_state() {
+        let _guard = crate::test::setup_test_store();
+
+        let is_bad_request = |state: &ModerationState| {
+            matches!(
+                import_state(state, ImportMode::Replace).map_err(|e| e.inner),
+                Err(ChorusError::BadRequest(_))
+            )
+        };
+
+        let mut bad = state();
+        bad.users[0].pubkey = "not hex".to_owned();
+        assert!(is_bad_request(&bad));
+
+        let mut bad = state();
+        bad.roles.clear();
+        assert!(is_bad_request(&bad));
+
+        let mut bad = state();
+        bad.ip_hash_keys = vec!["some other key".to_owned()];
+        assert!(is_bad_request(&bad));
+    }
+}
@@ -29,6 +29,10 @@ impl HashOutput {
         HashOutput(bytes)
     }
 
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
     pub fn to_pathbuf<P: AsRef<Path>>(&self, base: P) -> PathBuf {
         let s = hex::encode(self.0);
         let mut output: PathBuf = PathBuf::new();
@@ -170,4 +170,13 @@ impl FileStore {
 
         Ok(())
     }
+
+    /// Check that we can still write files (by writing and removing a temporary file)
+    pub async fn is_writable(&self) -> bool {
+        let probe = self.tmpfile();
+        if fs::write(&probe, b"").await.is_err() {
+            return false;
+        }
+        fs::remove_file(&probe).await.is_ok()
+    }
 }
@@ -0,0 +1,216 @@
+use crate::error::{ChorusError, Error};
+use crate::ip::IpCidr;
+use hyper::header::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Is the address within one of the trusted proxy networks?
+pub fn is_trusted_proxy(ip: IpAddr, trusted: &[IpCidr]) -> bool {
+    let ip = ip.to_canonical();
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The header our proxies give the client IP in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyIpHeader {
+    XRealIp,
+    XForwardedFor,
+    Forwarded,
+}
+
+impl FromStr for ProxyIpHeader {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProxyIpHeader, Error> {
+        match &*s.to_ascii_lowercase() {
+            "x-real-ip" => Ok(ProxyIpHeader::XRealIp),
+            "x-forwarded-for" => Ok(ProxyIpHeader::XForwardedFor),
+            "forwarded" => Ok(ProxyIpHeader::Forwarded),
+            _ => Err(ChorusError::General(
+                "proxy_ip_header must be x-real-ip, x-forwarded-for or forwarded".to_owned(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Get the client IP from the header set by a trusted proxy. Other headers are ignored,
+/// as clients may send them too. X-Real-Ip is taken as is. An X-Forwarded-For or
+/// Forwarded chain is read right to left, skipping our trusted proxies, and the first
+/// other address is the client.
+pub fn client_ip(
+    headers: &HeaderMap,
+    header: ProxyIpHeader,
+    trusted: &[IpCidr],
+) -> Result<IpAddr, Error> {
+    let mut chain: Vec<String> = Vec::new();
+    match header {
+        ProxyIpHeader::XRealIp => {
+            let rip = headers
+                .get("x-real-ip")
+                .ok_or(Into::<Error>::into(ChorusError::RealIpHeaderMissing))?;
+            let ripstr = rip
+                .to_str()
+                .map_err(|_| Into::<Error>::into(ChorusError::BadRealIpHeaderCharacters))?;
+            return parse_ip(ripstr)
+                .ok_or_else(|| ChorusError::BadRealIpHeader(ripstr.to_owned()).into());
+        }
+        ProxyIpHeader::XForwardedFor => {
+            for value in headers.get_all("x-forwarded-for") {
+                let value = value
+                    .to_str()
+                    .map_err(|_| Into::<Error>::into(ChorusError::BadRealIpHeaderCharacters))?;
+                chain.extend(value.split(',').map(|s| s.trim().to_owned()));
+            }
+        }
+        ProxyIpHeader::Forwarded => {
+            for value in headers.get_all("forwarded") {
+                let value = value
+                    .to_str()
+                    .map_err(|_| Into::<Error>::into(ChorusError::BadRealIpHeaderCharacters))?;
+                for element in value.split(',') {
+                    // Elements without a "for" parameter say nothing about the client
+                    if let Some(node) = element.split(';').find_map(|pair| {
+                        let (key, value) = pair.split_once('=')?;
+                        key.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then(|| value.trim().to_owned())
+                    }) {
+                        chain.push(node);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut leftmost: Option<IpAddr> = None;
+    for entry in chain.iter().rev() {
+        let ip = parse_ip(entry)
+            .ok_or_else(|| Into::<Error>::into(ChorusError::BadRealIpHeader(entry.to_owned())))?;
+        if !is_trusted_proxy(ip, trusted) {
+            return Ok(ip);
+        }
+        leftmost = Some(ip);
+    }
+
+    // Every hop was one of our proxies
+    leftmost.ok_or(ChorusError::RealIpHeaderMissing.into())
+}
+
+// Parse an address as found in forwarding headers: a bare IP, or quoted, or with a
+// port, or an IPv6 address in brackets
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+    let ip = s
+        .strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse::<IpAddr>()
+        .ok()?;
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_client_ip() {
+        use ProxyIpHeader::*;
+        let trusted: Vec<IpCidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        let h = headers(&[("x-real-ip", "203.0.113.7")]);
+        assert_eq!(client_ip(&h, XRealIp, &trusted).unwrap(), ip("203.0.113.7"));
+        assert!(client_ip(&h, XForwardedFor, &trusted).is_err());
+
+        // A spoofed entry on the left is ignored
+        let h = headers(&[("x-forwarded-for", "1.2.3.4, 203.0.113.7, 10.0.0.2")]);
+        assert_eq!(
+            client_ip(&h, XForwardedFor, &trusted).unwrap(),
+            ip("203.0.113.7")
+        );
+
+        // A spoofed X-Real-Ip passed on by a proxy that sets X-Forwarded-For is ignored
+        let h = headers(&[
+            ("x-real-ip", "1.2.3.4"),
+            ("x-forwarded-for", "203.0.113.7, 10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_ip(&h, XForwardedFor, &trusted).unwrap(),
+            ip("203.0.113.7")
+        );
+
+        // Chains can span several header lines
+        let h = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-for", "203.0.113.7:5555, 10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_ip(&h, XForwardedFor, &trusted).unwrap(),
+            ip("203.0.113.7")
+        );
+
+        let h = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            (
+                "forwarded",
+                "for=1.2.3.4, for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2;by=10.0.0.1",
+            ),
+        ]);
+        assert_eq!(
+            client_ip(&h, Forwarded, &trusted).unwrap(),
+            ip("2001:db8::7")
+        );
+
+        // Only proxies: the leftmost is the client
+        let h = headers(&[("x-forwarded-for", "10.1.1.1, 10.0.0.2")]);
+        assert_eq!(
+            client_ip(&h, XForwardedFor, &trusted).unwrap(),
+            ip("10.1.1.1")
+        );
+
+        let h = headers(&[("x-forwarded-for", "unknown, 10.0.0.2")]);
+        assert!(client_ip(&h, XForwardedFor, &trusted).is_err());
+
+        for header in [XRealIp, XForwardedFor, Forwarded] {
+            assert!(client_ip(&headers(&[]), header, &trusted).is_err());
+        }
+    }
+
+    #[test]
+    fn test_proxy_ip_header() {
+        assert_eq!(
+            "X-Forwarded-For".parse::<ProxyIpHeader>().unwrap(),
+            ProxyIpHeader::XForwardedFor
+        );
+        assert!("x-client-ip".parse::<ProxyIpHeader>().is_err());
+    }
+
+    #[test]
+    fn test_is_trusted_proxy() {
+        let trusted: Vec<IpCidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        assert!(is_trusted_proxy("10.3.2.1".parse().unwrap(), &trusted));
+        assert!(is_trusted_proxy(
+            "::ffff:10.3.2.1".parse().unwrap(),
+            &trusted
+        ));
+        assert!(!is_trusted_proxy("11.0.0.1".parse().unwrap(), &trusted));
+
+        // An empty list trusts nobody
+        assert!(!is_trusted_proxy("127.0.0.1".parse().unwrap(), &[]));
+    }
+}
@@ -1,27 +1,43 @@
 use crate::config::Config;
 use crate::filestore::FileStore;
-use crate::ip::HashedIp;
+use crate::ip::{HashedIp, IpHashKey};
+use crate::jobs::Job;
+use crate::session::Session;
+use crate::stats::Stats;
 use dashmap::DashMap;
-use hyper::server::conn::http1;
-use hyper_util::rt::tokio::TokioTimer;
+use hyper_util::rt::tokio::{TokioExecutor, TokioTimer};
+use hyper_util::server::conn::auto;
 use lazy_static::lazy_static;
 use parking_lot::RwLock;
 use pocket_db::Store;
+use pocket_types::{Id, Pubkey};
+use std::collections::BTreeSet;
 use std::sync::atomic::{AtomicU64, AtomicUsize};
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 use tokio::sync::broadcast::Sender as BroadcastSender;
 use tokio::sync::watch::Sender as WatchSender;
+use tokio_rustls::TlsAcceptor;
 
 pub struct Globals {
     pub start_time: Instant,
     pub bytes_inbound: AtomicU64,
     pub bytes_outbound: AtomicU64,
+
+    // Websocket traffic as it is before compression
+    pub bytes_inbound_uncompressed: AtomicU64,
+    pub bytes_outbound_uncompressed: AtomicU64,
     pub config: RwLock<Config>,
     pub store: OnceLock<Store>,
     pub filestore: OnceLock<FileStore>,
-    pub http1builder: http1::Builder,
-    pub rid: OnceLock<String>,
+    /// Serves HTTP/1.1 and HTTP/2 connections
+    pub httpbuilder: auto::Builder<TokioExecutor>,
+    /// The relay information document, built on first use and cleared when it changes
+    pub rid: RwLock<Option<String>>,
+
+    /// The TLS acceptor for new connections, if using TLS. Replaced when certificates
+    /// are reloaded; existing connections keep the one they were accepted with.
+    pub tls_acceptor: RwLock<Option<TlsAcceptor>>,
 
     /// This is a broadcast channel where new incoming events are advertised by their offset.
     /// Every handler needs to listen to it and check if the incoming event matches any
@@ -29,35 +45,94 @@ pub struct Globals {
     /// that subscription.
     pub new_events: BroadcastSender<u64>,
 
+    /// This is a broadcast channel of notifications (already JSON encoded) for moderators
+    /// listening on the websocket management channel.
+    pub management_notifications: BroadcastSender<String>,
+
     pub num_connections: AtomicUsize,
     pub num_connections_per_ip: DashMap<HashedIp, usize>,
+    pub num_connections_per_subnet: DashMap<HashedIp, usize>,
+
+    /// The distinct prefix lengths of manual IP blocks (None for single addresses),
+    /// so that checking an IP needs only one lookup per length
+    pub blocked_prefix_lens: RwLock<BTreeSet<Option<u8>>>,
+
+    /// Keys for hashing IP addresses, newest first. Older keys are kept to carry
+    /// reputation forward and to match manual blocks made under them.
+    pub ip_hash_keys: RwLock<Vec<IpHashKey>>,
+
+    /// Bumped whenever users, admins or roles change, so cached permissions are refreshed
+    pub permissions_generation: AtomicU64,
+
+    /// Admins from the database, and the permissions generation they were read at
+    pub admins: RwLock<Option<(u64, Vec<Pubkey>)>>,
     pub shutting_down: WatchSender<bool>,
+
+    /// Authorization events already used, with the unixtime when we can forget them
+    pub seen_auth_events: DashMap<Id, u64>,
+
+    /// Background jobs started via the management API
+    pub jobs: DashMap<u64, Arc<Job>>,
+    pub next_job_id: AtomicU64,
+
+    /// Live websocket sessions
+    pub sessions: DashMap<u64, Arc<Session>>,
+    pub next_session_id: AtomicU64,
+
+    /// Counters exported as metrics
+    pub stats: Stats,
 }
 
 lazy_static! {
     pub static ref GLOBALS: Globals = {
         let (new_events, _) = tokio::sync::broadcast::channel(512);
+        let (management_notifications, _) = tokio::sync::broadcast::channel(256);
         let (shutting_down, _) = tokio::sync::watch::channel(false);
 
-        let mut http1builder = http1::Builder::new();
-        http1builder.ignore_invalid_headers(true);
-        http1builder.keep_alive(true);
-        http1builder.timer(TokioTimer::new());
-        http1builder.header_read_timeout(Duration::from_secs(5));
+        let mut httpbuilder = auto::Builder::new(TokioExecutor::new());
+        httpbuilder
+            .http1()
+            .ignore_invalid_headers(true)
+            .keep_alive(true)
+            .timer(TokioTimer::new())
+            .header_read_timeout(Duration::from_secs(5));
+        // Extended CONNECT lets clients open websockets over HTTP/2 (RFC 8441)
+        httpbuilder
+            .http2()
+            .enable_connect_protocol()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(Duration::from_secs(60))
+            .keep_alive_timeout(Duration::from_secs(20))
+            .max_concurrent_streams(100);
 
         Globals {
             start_time: Instant::now(),
             bytes_inbound: AtomicU64::new(0),
             bytes_outbound: AtomicU64::new(0),
+            bytes_inbound_uncompressed: AtomicU64::new(0),
+            bytes_outbound_uncompressed: AtomicU64::new(0),
             config: RwLock::new(Default::default()),
             store: OnceLock::new(),
             filestore: OnceLock::new(),
-            http1builder,
-            rid: OnceLock::new(),
+            httpbuilder,
+            rid: RwLock::new(None),
+            tls_acceptor: RwLock::new(None),
             new_events,
+            management_notifications,
             num_connections: AtomicUsize::new(0),
             num_connections_per_ip: DashMap::new(),
+            num_connections_per_subnet: DashMap::new(),
+            blocked_prefix_lens: RwLock::new(BTreeSet::new()),
+            ip_hash_keys: RwLock::new(Vec::new()),
+            permissions_generation: AtomicU64::new(0),
+            admins: RwLock::new(None),
             shutting_down,
+            seen_auth_events: DashMap::new(),
+            jobs: DashMap::new(),
+            next_job_id: AtomicU64::new(1),
+            sessions: DashMap::new(),
+            next_session_id: AtomicU64::new(1),
+            stats: Stats::default(),
         }
     };
 }
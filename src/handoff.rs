@@ -0,0 +1,261 @@
+use crate::error::{ChorusError, Error};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{channel, Receiver};
+
+// Written by a new chorus process to ask the running one for its listening sockets
+const HANDOFF_REQUEST: &[u8; 16] = b"chorus-handoff/1";
+
+// The most sockets we will pass in one handoff
+const MAX_FDS: usize = 64;
+
+/// The longest a chorus that handed over may keep serving its websockets. New
+/// connections are not served until it has exited.
+pub const MAX_DRAIN_SECONDS: u64 = 30;
+
+// How long we wait for the running chorus to exit after it handed over: its drain,
+// plus the time it gives websockets to close and to sync the store
+const EXIT_TIMEOUT: Duration = Duration::from_secs(MAX_DRAIN_SECONDS + 30);
+
+/// Ask a running chorus listening on the handoff socket at `path` for its listening
+/// sockets. The running chorus stops accepting and hands them over at once, then keeps
+/// serving its websockets until they close (or for handoff_drain_seconds) and exits.
+/// This returns once it has exited, so that it has released the store.
+///
+/// This is not a zero-downtime handover: until the old chorus exits nobody accepts
+/// on the listening sockets, and new connections wait in the listen backlog (or are
+/// refused if it fills up).
+///
+/// Returns None if no chorus is listening on the handoff socket, or if it went away
+/// before handing over (its listening sockets are then closed and can be bound).
+pub async fn request_handoff(path: &str) -> Result<Option<Vec<OwnedFd>>, Error> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || request_handoff_blocking(&path))
+        .await
+        .map_err(|e| ChorusError::General(format!("Handoff: {e}")).into_err())?
+}
+
+fn request_handoff_blocking(path: &str) -> Result<Option<Vec<OwnedFd>>, Error> {
+    let mut stream = match StdUnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    stream.write_all(HANDOFF_REQUEST)?;
+
+    // The running chorus answers as soon as it reads our request
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let fds = recv_fds(&stream)?;
+    if fds.is_empty() {
+        log::warn!(target: "Server", "Handoff: the running chorus went away, binding instead");
+        return Ok(None);
+    }
+
+    // The old process closes its end when it exits, however long it takes to drain.
+    // Wait for that so that it has released the store and everything else it had.
+    log::info!(target: "Server", "Handoff: waiting for the running chorus to exit");
+    stream.set_read_timeout(Some(EXIT_TIMEOUT))?;
+    let mut rest: Vec<u8> = Vec::new();
+    match stream.read_to_end(&mut rest) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            Err(ChorusError::General("Handoff: the running chorus did not exit".to_owned()).into())
+        }
+        _ => Ok(Some(fds)),
+    }
+}
+
+/// Listen on the handoff socket at `path` for a new chorus process. The returned
+/// channel yields the connection once a valid request has been read from it by a
+/// process running as our user. Any stale socket file at `path` is replaced.
+pub fn listen_for_handoff(path: &str) -> Result<Receiver<StdUnixStream>, Error> {
+    let _ = std::fs::remove_file(path);
+
+    // Create the socket file accessible only to us, rather than chmod it after
+    // binding when others could already connect
+    // SAFETY: umask cannot fail
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+
+    // SAFETY: geteuid cannot fail
+    let uid = unsafe { libc::geteuid() };
+
+    let (sender, receiver) = channel(1);
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!(target: "Server", "Handoff listener: {}", e);
+                    continue;
+                }
+            };
+            if !matches!(stream.peer_cred(), Ok(cred) if cred.uid() == uid) {
+                log::warn!(target: "Server", "Handoff: ignoring request from another user");
+                continue;
+            }
+            let mut request = [0u8; HANDOFF_REQUEST.len()];
+            let read =
+                tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut request)).await;
+            if !matches!(read, Ok(Ok(_))) || &request != HANDOFF_REQUEST {
+                log::warn!(target: "Server", "Handoff: ignoring invalid request");
+                continue;
+            }
+            let Ok(stream) = stream.into_std() else {
+                continue;
+            };
+            if stream.set_nonblocking(false).is_ok() {
+                let _ = sender.send(stream).await;
+                return;
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+/// Hand listening sockets to the new chorus process over its handoff connection
+pub fn send_listeners(stream: &StdUnixStream, fds: &[RawFd]) -> Result<(), Error> {
+    if fds.is_empty() || fds.len() > MAX_FDS {
+        return Err(ChorusError::General("Handoff: bad number of sockets".to_owned()).into());
+    }
+
+    let payload: [u8; 1] = [fds.len() as u8];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let fds_len = std::mem::size_of_val(fds) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+
+    // SAFETY: msghdr is plain data, and the control buffer has room for one
+    // header carrying all of the fds.
+    let sent = unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Receive the listening sockets sent by send_listeners(). There are none if the
+// sender went away first.
+fn recv_fds(stream: &StdUnixStream) -> Result<Vec<OwnedFd>, Error> {
+    let mut payload: [u8; 1] = [0];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let max_len = (MAX_FDS * std::mem::size_of::<RawFd>()) as libc::c_uint;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(max_len) } as usize];
+
+    let mut fds: Vec<OwnedFd> = Vec::new();
+
+    // SAFETY: the kernel fills in at most the buffers we describe, and we only read
+    // control messages it reports.
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let received = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::ConnectionReset {
+                return Ok(fds);
+            }
+            return Err(e.into());
+        }
+
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(ChorusError::General("Handoff: sockets truncated".to_owned()).into());
+        }
+    }
+
+    if fds.len() != payload[0] as usize {
+        return Err(ChorusError::General("Handoff: socket count mismatch".to_owned()).into());
+    }
+
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let dir = std::env::temp_dir().join(format!("chorus-handoff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("handoff").to_string_lossy().into_owned();
+
+        // Only we may connect
+        let mut requests = listen_for_handoff(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let request = {
+            let path = path.clone();
+            tokio::spawn(async move { request_handoff(&path).await })
+        };
+        let stream = requests.recv().await.unwrap();
+        send_listeners(&stream, &[listener.as_raw_fd()]).unwrap();
+        drop(stream);
+
+        let fds = request.await.unwrap().unwrap().unwrap();
+        assert_eq!(fds.len(), 1);
+        let received = std::net::TcpListener::from(fds.into_iter().next().unwrap());
+        assert_eq!(received.local_addr().unwrap(), address);
+
+        // If the running chorus goes away without handing over, we bind instead
+        let mut requests = listen_for_handoff(&path).unwrap();
+        let request = {
+            let path = path.clone();
+            tokio::spawn(async move { request_handoff(&path).await })
+        };
+        drop(requests.recv().await.unwrap());
+        assert!(request.await.unwrap().unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
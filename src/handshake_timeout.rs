@@ -0,0 +1,101 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// How many bytes we read to tell HTTP/1.1 from HTTP/2 (the length of the HTTP/2
+/// connection preface)
+pub const SNIFF_BYTES: usize = 24;
+
+/// A stream whose reads fail with TimedOut if the client has not sent the first
+/// SNIFF_BYTES bytes in time, so that connections which never speak cannot be held
+/// open while we wait to learn their protocol. After that, reads pass through.
+#[derive(Debug)]
+pub struct HandshakeTimeout<S> {
+    stream: S,
+    deadline: Option<Pin<Box<Sleep>>>,
+    received: usize,
+}
+
+impl<S> HandshakeTimeout<S> {
+    pub fn new(stream: S, timeout: Duration) -> HandshakeTimeout<S> {
+        HandshakeTimeout {
+            stream,
+            deadline: Some(Box::pin(sleep(timeout))),
+            received: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for HandshakeTimeout<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let pre = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let Some(deadline) = &mut this.deadline {
+            this.received += buf.filled().len() - pre;
+            if this.received >= SNIFF_BYTES || matches!(result, Poll::Ready(Err(_))) {
+                this.deadline = None;
+            } else if result.is_pending() && deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for the client to speak",
+                )));
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for HandshakeTimeout<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        // A client that never speaks times out
+        let (_client, server) = tokio::io::duplex(64);
+        let mut server = HandshakeTimeout::new(server, Duration::from_millis(50));
+        let mut buf = [0u8; 8];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Once it has spoken, it may go quiet for longer
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = HandshakeTimeout::new(server, Duration::from_millis(50));
+        client.write_all(&[b'x'; SNIFF_BYTES]).await.unwrap();
+        let mut buf = [0u8; SNIFF_BYTES];
+        server.read_exact(&mut buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.write_all(b"more").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"more");
+    }
+}
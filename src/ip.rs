@@ -1,6 +1,159 @@
+use crate::error::{ChorusError, Error};
+use crate::globals::GLOBALS;
 use pocket_types::Time;
 use speedy::{Readable, Writable};
-use std::net::{IpAddr, SocketAddr};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A secret key for hashing IP addresses, and when it was created. The legacy key
+/// (None) is the unkeyed hash used before keys were introduced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpHashKey {
+    pub created_at: u64,
+    pub key: Option<[u8; 32]>,
+}
+
+impl IpHashKey {
+    pub const LEGACY: IpHashKey = IpHashKey {
+        created_at: 0,
+        key: None,
+    };
+
+    /// Generate a new random key
+    pub fn generate() -> Result<IpHashKey, Error> {
+        let mut key = [0u8; 32];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(IpHashKey {
+            created_at: Time::now().as_u64(),
+            key: Some(key),
+        })
+    }
+
+    // Hash bytes into the 20 character tag of a HashedIp
+    fn tag(&self, bytes: &[u8]) -> [u8; 20] {
+        use base64::prelude::*;
+        use secp256k1::hashes::{hmac, sha256, Hash, HashEngine};
+        let digest: [u8; 32] = match &self.key {
+            Some(key) => {
+                let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+                engine.input(bytes);
+                hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+            }
+            None => sha256::Hash::hash(bytes).to_byte_array(),
+        };
+        let tag = BASE64_STANDARD.encode(&digest[0..16]);
+        tag.as_bytes()[..20].try_into().unwrap()
+    }
+
+    /// A short fingerprint identifying this key without revealing it
+    pub fn fingerprint(&self) -> String {
+        String::from_utf8_lossy(&self.tag(b"chorus ip hash key fingerprint")).into_owned()
+    }
+}
+
+/// The IP hash keys in use, newest (current) first
+pub fn ip_hash_keys() -> Vec<IpHashKey> {
+    let keys = GLOBALS.ip_hash_keys.read();
+    if keys.is_empty() {
+        vec![IpHashKey::LEGACY]
+    } else {
+        keys.clone()
+    }
+}
+
+/// Parse an IP address, a CIDR network, or a hashed IP as displayed in our logs.
+/// Returns the hashes it may be stored under (with each IP hash key, the current key
+/// first) along with the network prefix length (if a network)
+pub fn parse_hashed_ip(text: &str) -> Option<(Vec<HashedIp>, Option<u8>)> {
+    parse_hashed_ip_with_keys(text, &ip_hash_keys())
+}
+
+// Addresses are canonicalized (e.g. IPv4-mapped IPv6 to IPv4) as they are when
+// connections are looked up, so that both hash the same bytes
+fn parse_hashed_ip_with_keys(
+    text: &str,
+    keys: &[IpHashKey],
+) -> Option<(Vec<HashedIp>, Option<u8>)> {
+    if let Ok(ipaddr) = text.parse::<IpAddr>() {
+        let ipaddr = ipaddr.to_canonical();
+        let ips = keys
+            .iter()
+            .map(|k| HashedIp::new_with_key(ipaddr, k))
+            .collect();
+        Some((ips, None))
+    } else if let Ok(cidr) = text.parse::<IpCidr>() {
+        let cidr = cidr.to_canonical();
+        if cidr.is_single_address() {
+            let ips = keys
+                .iter()
+                .map(|k| HashedIp::new_with_key(cidr.network(), k))
+                .collect();
+            Some((ips, None))
+        } else {
+            let ips = keys
+                .iter()
+                .map(|k| HashedIp::new_network_with_key(cidr, k))
+                .collect();
+            Some((ips, Some(cidr.prefix_len())))
+        }
+    } else {
+        HashedIp::from_tag(text).map(|hashed_ip| (vec![hashed_ip], None))
+    }
+}
+
+/// Read IP hash keys from the key file, newest first. Returns None if there is no file.
+pub fn read_ip_hash_keys(path: &Path) -> Result<Option<Vec<IpHashKey>>, Error> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let bad = || Into::<Error>::into(ChorusError::General("Bad IP hash key file".to_owned()));
+    let mut keys: Vec<IpHashKey> = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let (created_at, key) = line.trim().split_once(' ').ok_or_else(bad)?;
+        let created_at = created_at.parse::<u64>().map_err(|_| bad())?;
+        let key = if key == "legacy" {
+            None
+        } else {
+            let bytes = hex::decode(key).map_err(|_| bad())?;
+            Some(bytes.try_into().map_err(|_| bad())?)
+        };
+        keys.push(IpHashKey { created_at, key });
+    }
+    Ok(Some(keys))
+}
+
+/// Write IP hash keys to the key file, readable only by us
+pub fn write_ip_hash_keys(path: &Path, keys: &[IpHashKey]) -> Result<(), Error> {
+    let mut contents = String::new();
+    for key in keys {
+        let key_text = match &key.key {
+            Some(k) => hex::encode(k),
+            None => "legacy".to_owned(),
+        };
+        contents.push_str(&format!("{} {}\n", key.created_at, key_text));
+    }
+
+    // Write a new file and rename it over the old one
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct HashedIp(pub [u8; 20], bool);
@@ -12,29 +165,54 @@ impl std::fmt::Display for HashedIp {
 }
 
 impl HashedIp {
+    /// Hash of an IP address with the current key
     pub fn new(ip_addr: IpAddr) -> HashedIp {
-        use base64::prelude::*;
-        use secp256k1::hashes::{sha256, Hash};
+        HashedIp::new_with_key(ip_addr, &ip_hash_keys()[0])
+    }
+
+    pub fn new_with_key(ip_addr: IpAddr, key: &IpHashKey) -> HashedIp {
         let bytes = ip_addr.write_to_vec().unwrap();
-        let hashvalue: sha256::Hash = Hash::hash(&bytes);
-        let tag = BASE64_STANDARD.encode(&hashvalue.as_byte_array()[0..16]);
-        HashedIp(
-            tag.as_bytes()[..20].try_into().unwrap(),
-            ip_addr.is_loopback(),
-        )
+        HashedIp(key.tag(&bytes), ip_addr.is_loopback())
+    }
+
+    /// Hash of a network (the address masked to prefix_len bits, and the prefix_len itself)
+    /// with the current key
+    pub fn new_network(cidr: IpCidr) -> HashedIp {
+        HashedIp::new_network_with_key(cidr, &ip_hash_keys()[0])
+    }
+
+    pub fn new_network_with_key(cidr: IpCidr, key: &IpHashKey) -> HashedIp {
+        let mut bytes = cidr.network().write_to_vec().unwrap();
+        bytes.push(cidr.prefix_len());
+        HashedIp(key.tag(&bytes), false)
     }
 
     pub fn from_bytes(bytes: &[u8]) -> HashedIp {
         HashedIp(bytes[0..20].try_into().unwrap(), false)
     }
 
+    /// Parse a HashedIp from its displayed form (20 base64 characters)
+    pub fn from_tag(tag: &str) -> Option<HashedIp> {
+        let bytes = tag.as_bytes();
+        if bytes.len() == 20
+            && bytes
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+        {
+            Some(HashedIp::from_bytes(bytes))
+        } else {
+            None
+        }
+    }
+
     pub fn is_loopback(&self) -> bool {
         self.1
     }
 }
 
+/// A hashed IP and port, along with the hash of the IP's subnet (if it has one)
 #[derive(Debug, Clone, Copy)]
-pub struct HashedPeer(pub HashedIp, pub u16);
+pub struct HashedPeer(pub HashedIp, pub u16, pub Option<HashedIp>);
 
 impl std::fmt::Display for HashedPeer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -45,11 +223,8 @@ impl std::fmt::Display for HashedPeer {
 impl HashedPeer {
     pub fn new(peer_addr: SocketAddr) -> HashedPeer {
         let hashed_ip = HashedIp::new(peer_addr.ip());
-        HashedPeer(hashed_ip, peer_addr.port())
-    }
-
-    pub fn from_parts(hashed_ip: HashedIp, port: u16) -> HashedPeer {
-        HashedPeer(hashed_ip, port)
+        let subnet = subnet_of(peer_addr.ip()).map(HashedIp::new_network);
+        HashedPeer(hashed_ip, peer_addr.port(), subnet)
     }
 
     pub fn ip(&self) -> HashedIp {
@@ -59,6 +234,132 @@ impl HashedPeer {
     pub fn port(&self) -> u16 {
         self.1
     }
+
+    /// The hashed subnet, which shares reputation, bans and a connection cap
+    pub fn subnet(&self) -> Option<HashedIp> {
+        self.2
+    }
+}
+
+/// The subnet an IP address is grouped into, per subnet_prefix_len_v4 and
+/// subnet_prefix_len_v6. Loopback addresses, and addresses when the prefix length
+/// is the full address, are not grouped.
+pub fn subnet_of(ip_addr: IpAddr) -> Option<IpCidr> {
+    let ip_addr = ip_addr.to_canonical();
+    if ip_addr.is_loopback() {
+        return None;
+    }
+    let (prefix_len, max) = {
+        let config = GLOBALS.config.read();
+        match ip_addr {
+            IpAddr::V4(_) => (config.subnet_prefix_len_v4, 32),
+            IpAddr::V6(_) => (config.subnet_prefix_len_v6, 128),
+        }
+    };
+    if prefix_len >= max {
+        return None;
+    }
+    IpCidr::new(ip_addr, prefix_len).ok()
+}
+
+/// An IP address with a prefix length, e.g. 192.168.0.0/16 or 2001:db8::/32
+///
+/// A bare IP address parses as a single-address network (/32 or /128)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<IpCidr, Error> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max {
+            return Err(ChorusError::InvalidCidr(format!("{addr}/{prefix_len}")).into());
+        }
+        Ok(IpCidr { addr, prefix_len })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The same network with an IPv4-mapped IPv6 address as IPv4, if it is one
+    pub fn to_canonical(&self) -> IpCidr {
+        match self.addr {
+            IpAddr::V6(a) if self.prefix_len >= 96 => match a.to_ipv4_mapped() {
+                Some(v4) => IpCidr {
+                    addr: IpAddr::V4(v4),
+                    prefix_len: self.prefix_len - 96,
+                },
+                None => *self,
+            },
+            _ => *self,
+        }
+    }
+
+    /// Is this a single address (not a larger network)?
+    pub fn is_single_address(&self) -> bool {
+        match self.addr {
+            IpAddr::V4(_) => self.prefix_len == 32,
+            IpAddr::V6(_) => self.prefix_len == 128,
+        }
+    }
+
+    /// The network address (host bits zeroed)
+    pub fn network(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V4(a) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask))
+            }
+            IpAddr::V6(a) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask))
+            }
+        }
+    }
+
+    /// Does this network contain the address?
+    pub fn contains(&self, ip_addr: IpAddr) -> bool {
+        match IpCidr::new(ip_addr, self.prefix_len) {
+            Ok(other) => other.network() == self.network(),
+            Err(_) => false, // different address family
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<IpCidr, Error> {
+        let bad = || Into::<Error>::into(ChorusError::InvalidCidr(s.to_owned()));
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr = addr.trim().parse::<IpAddr>().map_err(|_| bad())?;
+                let len = len.trim().parse::<u8>().map_err(|_| bad())?;
+                IpCidr::new(addr, len).map_err(|_| bad())
+            }
+            None => {
+                let addr = s.trim().parse::<IpAddr>().map_err(|_| bad())?;
+                let len = if addr.is_ipv4() { 32 } else { 128 };
+                IpCidr::new(addr, len)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network(), self.prefix_len)
+    }
 }
 
 // Single-session exit condition
@@ -80,6 +381,7 @@ pub enum SessionExit {
 
 // Long term reputation of an IP address
 // The values are running totals, updating with (9/10) of old and (1/10) of new.
+// They also decay over time (see IpData::decay_to).
 //
 // Used to determine ban time multiplier from short-term violations
 #[derive(Debug, Clone, Default, Readable, Writable)]
@@ -106,6 +408,25 @@ impl IpReputation {
         };
     }
 
+    fn scale(&mut self, factor: f32) {
+        self.good *= factor;
+        self.errored *= factor;
+        self.too_many_errors *= factor;
+        self.timed_out *= factor;
+    }
+
+    // Whether there is nothing left worth remembering
+    fn is_negligible(&self) -> bool {
+        [
+            self.good,
+            self.errored,
+            self.too_many_errors,
+            self.timed_out,
+        ]
+        .iter()
+        .all(|v| *v < 0.01)
+    }
+
     pub fn ban_multiplier(&self) -> f32 {
         let good_endings = 1.0 + self.good + (self.errored / 2.0);
 
@@ -115,20 +436,66 @@ impl IpReputation {
     }
 }
 
-// Memory-only short-term record of IP handling
+// Record of IP handling: a short-term ban and long-term reputation
 #[derive(Debug, Clone, Default, Readable, Writable)]
 pub struct IpData {
     pub ban_until: u64,
     pub reputation: IpReputation,
+
+    // When the reputation was last decayed
+    pub last_update: u64,
+}
+
+// IpData as stored before reputation decayed over time
+#[derive(Readable)]
+struct IpDataV1 {
+    ban_until: u64,
+    reputation: IpReputation,
 }
 
 impl IpData {
+    /// Read IpData as stored, including records from before last_update was added
+    pub fn from_bytes(bytes: &[u8]) -> Result<IpData, Error> {
+        match IpData::read_from_buffer(bytes) {
+            Ok(ip_data) => Ok(ip_data),
+            Err(_) => {
+                let v1 = IpDataV1::read_from_buffer(bytes)?;
+                // The last ban began when the last session closed, which is near enough
+                Ok(IpData {
+                    ban_until: v1.ban_until,
+                    reputation: v1.reputation,
+                    last_update: v1.ban_until,
+                })
+            }
+        }
+    }
+
+    /// Decay the reputation for the time elapsed since the last update, halving it
+    /// every half_life_seconds (if not zero)
+    pub fn decay_to(&mut self, now: u64, half_life_seconds: u64) {
+        if half_life_seconds > 0 && now > self.last_update {
+            let half_lives = (now - self.last_update) as f64 / half_life_seconds as f64;
+            self.reputation.scale(0.5_f64.powf(half_lives) as f32);
+        }
+        self.last_update = self.last_update.max(now);
+    }
+
+    /// Whether this record can be dropped: not banned, and with a reputation that
+    /// has decayed away
+    pub fn is_stale(&self, now: u64, half_life_seconds: u64) -> bool {
+        let mut decayed = self.clone();
+        decayed.decay_to(now, half_life_seconds);
+        !self.is_banned() && decayed.reputation.is_negligible()
+    }
+
     pub fn update_on_session_close(
         &mut self,
         session_exit: SessionExit,
         minimum_ban_seconds: u64,
+        half_life_seconds: u64,
     ) -> u64 {
         // Update reputation
+        self.decay_to(Time::now().as_u64(), half_life_seconds);
         self.reputation.update(session_exit);
 
         // Compute ban_until
@@ -157,6 +524,20 @@ impl IpData {
     }
 }
 
+// A manual block placed by a moderator. These do not expire.
+#[derive(Debug, Clone, Default, Readable, Writable)]
+pub struct IpBlock {
+    pub reason: String,
+    pub created_at: u64,
+
+    // Set if this blocks an entire network rather than a single address
+    pub prefix_len: Option<u8>,
+
+    // When the IP hash key it was hashed with was made. None if it was given as a
+    // hash, which may have been made with any key we had at created_at.
+    pub key_created_at: Option<u64>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -169,4 +550,118 @@ mod test {
         let socketaddr = std::net::SocketAddr::new(ipaddr, 80);
         println!("HashedPEER={}", HashedPeer::new(socketaddr));
     }
+
+    #[test]
+    fn test_ip_cidr() {
+        let cidr: IpCidr = "192.168.7.9/16".parse().unwrap();
+        assert_eq!(&format!("{cidr}"), "192.168.0.0/16");
+        assert!(cidr.contains("192.168.200.1".parse().unwrap()));
+        assert!(!cidr.contains("192.169.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let cidr: IpCidr = "2001:db8:1:2::5/64".parse().unwrap();
+        assert_eq!(&format!("{cidr}"), "2001:db8:1:2::/64");
+        assert!(cidr.contains("2001:db8:1:2:ffff::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db8:1:3::1".parse().unwrap()));
+
+        let single: IpCidr = "10.0.0.1".parse().unwrap();
+        assert!(single.is_single_address());
+        assert!("0.0.0.0/0"
+            .parse::<IpCidr>()
+            .unwrap()
+            .contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("nonsense".parse::<IpCidr>().is_err());
+
+        // An explicit key, as other tests may rotate the global ones meanwhile
+        let key = IpHashKey {
+            created_at: 1000,
+            key: Some([1; 32]),
+        };
+        let a = HashedIp::new_network_with_key("10.1.2.3/24".parse().unwrap(), &key);
+        let b = HashedIp::new_network_with_key("10.1.2.200/24".parse().unwrap(), &key);
+        let c = HashedIp::new_network_with_key("10.1.2.3/25".parse().unwrap(), &key);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(HashedIp::from_tag(&format!("{a}")), Some(a));
+    }
+
+    #[test]
+    fn test_parse_hashed_ip() {
+        let keys = [IpHashKey {
+            created_at: 1000,
+            key: Some([1; 32]),
+        }];
+        let parse = |text: &str| parse_hashed_ip_with_keys(text, &keys).unwrap();
+
+        // IPv4-mapped IPv6 hashes as the IPv4 address it maps
+        let (ips, prefix_len) = parse("::ffff:10.1.2.3");
+        assert_eq!(ips, parse("10.1.2.3").0);
+        assert_eq!(
+            ips[0],
+            HashedIp::new_with_key("10.1.2.3".parse().unwrap(), &keys[0])
+        );
+        assert_eq!(prefix_len, None);
+
+        let (ips, prefix_len) = parse("::ffff:10.1.2.0/120");
+        assert_eq!(ips, parse("10.1.2.0/24").0);
+        assert_eq!(prefix_len, Some(24));
+        let subnet = IpCidr::new("10.1.2.3".parse().unwrap(), 24).unwrap();
+        assert_eq!(ips[0], HashedIp::new_network_with_key(subnet, &keys[0]));
+
+        assert_ne!(parse("::ffff:10.1.2.3").0, parse("::10.1.2.3").0);
+        let (ips, _) = parse(&format!("{}", ips[0]));
+        assert_eq!(ips.len(), 1);
+    }
+
+    #[test]
+    fn test_ip_data() {
+        // Stored before last_update was added
+        let mut bytes: Vec<u8> = 5000_u64.to_le_bytes().to_vec();
+        for v in [1.0_f32, 2.0, 0.0, 4.0] {
+            bytes.extend(v.to_le_bytes());
+        }
+        let mut ip_data = IpData::from_bytes(&bytes).unwrap();
+        assert_eq!(ip_data.last_update, 5000);
+        assert_eq!(ip_data.reputation.errored, 2.0);
+
+        let bytes = ip_data.write_to_vec().unwrap();
+        assert_eq!(IpData::from_bytes(&bytes).unwrap().last_update, 5000);
+
+        // Two half lives later
+        ip_data.decay_to(5200, 100);
+        assert_eq!(ip_data.last_update, 5200);
+        assert_eq!(ip_data.reputation.errored, 0.5);
+        assert_eq!(ip_data.reputation.timed_out, 1.0);
+
+        // No decay
+        ip_data.decay_to(9000, 0);
+        assert_eq!(ip_data.reputation.errored, 0.5);
+        assert_eq!(ip_data.last_update, 9000);
+    }
+
+    #[test]
+    fn test_ip_hash_keys() {
+        let ipaddr: IpAddr = "203.0.113.7".parse().unwrap();
+        let key1 = IpHashKey {
+            created_at: 1000,
+            key: Some([1; 32]),
+        };
+        let key2 = IpHashKey {
+            created_at: 2000,
+            key: Some([2; 32]),
+        };
+        let legacy = HashedIp::new_with_key(ipaddr, &IpHashKey::LEGACY);
+        let hashed1 = HashedIp::new_with_key(ipaddr, &key1);
+        assert_eq!(hashed1, HashedIp::new_with_key(ipaddr, &key1));
+        assert_ne!(hashed1, legacy);
+        assert_ne!(hashed1, HashedIp::new_with_key(ipaddr, &key2));
+
+        let path = std::env::temp_dir().join(format!("chorus-test-keys-{}", std::process::id()));
+        let keys = vec![key2, key1, IpHashKey::LEGACY];
+        write_ip_hash_keys(&path, &keys).unwrap();
+        assert_eq!(read_ip_hash_keys(&path).unwrap(), Some(keys));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_ip_hash_keys(&path).unwrap(), None);
+    }
 }
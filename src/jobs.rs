@@ -0,0 +1,170 @@
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use parking_lot::RwLock;
+use pocket_types::{Pubkey, Time};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How long (in seconds) we remember jobs after they end
+const ENDED_JOB_RETENTION: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed,
+}
+
+/// A long running background task started via the management API
+#[derive(Debug)]
+pub struct Job {
+    pub id: u64,
+    pub description: String,
+    pub started: Time,
+    pub total: AtomicU64,
+    pub done: AtomicU64,
+    cancel: AtomicBool,
+    state: RwLock<(JobState, Option<Time>, Option<String>)>,
+}
+
+/// A snapshot of a job, suitable for reporting
+#[derive(Debug, Clone, Serialize)]
+pub struct JobReport {
+    pub id: u64,
+    pub description: String,
+    pub state: JobState,
+    pub started: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended: Option<u64>,
+    pub done: u64,
+    pub total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    fn new(description: String) -> Job {
+        Job {
+            id: GLOBALS.next_job_id.fetch_add(1, Ordering::Relaxed),
+            description,
+            started: Time::now(),
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            cancel: AtomicBool::new(false),
+            state: RwLock::new((JobState::Running, None, None)),
+        }
+    }
+
+    /// Ask the job to stop at its next opportunity
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) -> JobReport {
+        let (state, ended, error) = self.state.read().clone();
+        JobReport {
+            id: self.id,
+            description: self.description.clone(),
+            state,
+            started: self.started.as_u64(),
+            ended: ended.map(|t| t.as_u64()),
+            done: self.done.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            error,
+        }
+    }
+
+    fn end(&self, result: Result<(), Error>) {
+        let state = match result {
+            Ok(()) if self.is_cancelled() => (JobState::Cancelled, None),
+            Ok(()) => (JobState::Finished, None),
+            Err(e) => (JobState::Failed, Some(format!("{e}"))),
+        };
+        log::info!(target: "Server", "Job {} ({}) ended: {:?}", self.id, self.description, state.0);
+        *self.state.write() = (state.0, Some(Time::now()), state.1);
+    }
+}
+
+/// Get a job by its id
+pub fn get_job(id: u64) -> Option<Arc<Job>> {
+    GLOBALS.jobs.get(&id).map(|j| j.value().clone())
+}
+
+/// Report on all remembered jobs, oldest first
+pub fn list_jobs() -> Vec<JobReport> {
+    let mut reports: Vec<JobReport> = GLOBALS.jobs.iter().map(|j| j.value().report()).collect();
+    reports.sort_by_key(|r| r.id);
+    reports
+}
+
+// Register a new job, forgetting jobs that ended long ago
+fn register_job(description: String) -> Arc<Job> {
+    let now = Time::now().as_u64();
+    GLOBALS.jobs.retain(|_, j| match j.state.read().1 {
+        Some(ended) => ended.as_u64() + ENDED_JOB_RETENTION > now,
+        None => true,
+    });
+
+    let job = Arc::new(Job::new(description));
+    GLOBALS.jobs.insert(job.id, job.clone());
+    log::info!(target: "Server", "Job {} ({}) started", job.id, job.description);
+    job
+}
+
+/// Start a job that removes all events and blossom blobs from a pubkey.
+/// Returns the job id.
+pub fn start_purge_pubkey(pubkey: Pubkey) -> u64 {
+    let job = register_job(format!("purge {}", pubkey.as_hex_string()));
+    let id = job.id;
+    tokio::spawn(async move {
+        let result = purge_pubkey(&job, pubkey).await;
+        job.end(result);
+    });
+    id
+}
+
+async fn purge_pubkey(job: &Job, pubkey: Pubkey) -> Result<(), Error> {
+    let ids = crate::get_event_ids_by_pubkey(pubkey)?;
+    let blobs = crate::get_blob_uploads_by_pubkey(pubkey)?;
+    job.total
+        .store((ids.len() + blobs.len()) as u64, Ordering::Relaxed);
+
+    for id in ids {
+        if job.is_cancelled() {
+            return Ok(());
+        }
+        GLOBALS.store.get().unwrap().remove_event(id)?;
+        let done = job.done.fetch_add(1, Ordering::Relaxed);
+
+        // Don't hog the runtime
+        if done % 100 == 99 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    for hash in blobs {
+        if job.is_cancelled() {
+            return Ok(());
+        }
+
+        // Only delete the file if nobody else uploaded it too
+        if !crate::blob_has_other_uploader(hash, pubkey)? {
+            if let Some(filestore) = GLOBALS.filestore.get() {
+                if let Err(e) = filestore.delete(hash).await {
+                    log::warn!(target: "Server", "Job {}: could not delete blob {}: {}", job.id, hash, e);
+                }
+            }
+        }
+        crate::remove_blob_upload(pubkey, hash)?;
+        job.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
@@ -1,64 +1,99 @@
 pub mod config;
 pub mod counting_stream;
+pub mod deflate;
 pub mod error;
+pub mod export;
 pub mod filestore;
+pub mod forwarded;
 pub mod globals;
+pub mod handoff;
+pub mod handshake_timeout;
 pub mod ip;
+pub mod jobs;
+pub mod listener;
+pub mod logging;
 mod neg_storage;
 pub mod nostr;
+pub mod proxy_protocol;
 pub mod reply;
+pub mod roles;
+pub mod session;
+pub mod stats;
 pub mod tls;
 pub mod web;
 
 use crate::config::{Config, FriendlyConfig};
+use crate::counting_stream::CountingStream;
+use crate::deflate::{DeflateParams, DeflateStream};
 use crate::error::{ChorusError, Error};
+use crate::filestore::HashOutput;
 use crate::globals::GLOBALS;
-use crate::ip::{HashedIp, HashedPeer, IpData, SessionExit};
+use crate::handshake_timeout::HandshakeTimeout;
+use crate::ip::{HashedIp, HashedPeer, IpBlock, IpCidr, IpData, IpHashKey, SessionExit};
 use crate::reply::NostrReply;
+use crate::roles::{Permission, PermissionSet};
+use crate::session::Session;
 use futures::{sink::SinkExt, stream::StreamExt};
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty};
 use hyper::body::{Bytes, Incoming};
 use hyper::service::Service;
-use hyper::upgrade::Upgraded;
+use hyper::upgrade::{OnUpgrade, Upgraded};
 use hyper::StatusCode;
 use hyper::{Request, Response};
 use hyper_tungstenite::tungstenite;
-use hyper_tungstenite::{HyperWebsocket, WebSocketStream};
+use hyper_tungstenite::WebSocketStream;
 use hyper_util::rt::TokioIo;
 use neg_storage::NegentropyStorageVector;
 use pocket_db::{ScreenResult, Store};
-use pocket_types::{Id, OwnedFilter, Pubkey};
+use pocket_types::{Filter, Id, OwnedFilter, Pubkey, Tags, Time};
 use speedy::{Readable, Writable};
 use std::collections::HashMap;
-use std::error::Error as StdError;
 use std::fs::OpenOptions;
 use std::future::Future;
 use std::io::Read;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::pin::Pin;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use textnonce::TextNonce;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver as BroadcastReceiver;
 use tokio::time::Instant;
+use tungstenite::error::ProtocolError;
+use tungstenite::handshake::derive_accept_key;
 use tungstenite::protocol::frame::Utf8Bytes;
-use tungstenite::protocol::WebSocketConfig;
+use tungstenite::protocol::{Role, WebSocketConfig};
 use tungstenite::Message;
 
 /// Serve a single network connection
-pub async fn serve<T>(stream: TokioIo<T>, peer: HashedPeer)
-where
+pub async fn serve<T>(
+    stream: TokioIo<T>,
+    peer: HashedPeer,
+    source: Option<IpAddr>,
+    behind_proxy: bool,
+) where
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     // Serve the network stream with our http server and our ChorusService
-    let service = ChorusService { peer };
+    let service = ChorusService {
+        peer,
+        source,
+        behind_proxy,
+    };
+
+    // Give up on clients that do not send enough for us to tell their protocol
+    let stream = TokioIo::new(HandshakeTimeout::new(
+        stream.into_inner(),
+        Duration::from_secs(5),
+    ));
 
-    let http1builder = GLOBALS.http1builder.clone();
-    let connection = http1builder
-        .serve_connection(stream, service)
-        .with_upgrades();
+    let connection = GLOBALS
+        .httpbuilder
+        .serve_connection_with_upgrades(stream, service);
 
     // If our service exits with an error, log the error
     if let Err(he) = connection.await {
@@ -71,8 +106,7 @@ where
             }
         } else {
             // Print in less detail
-            let e: Error = he.into();
-            log::error!(target: "Client", "{}: {}", peer, e);
+            log::error!(target: "Client", "{}: {}", peer, he);
         }
     }
 }
@@ -80,6 +114,13 @@ where
 // This is our per-connection HTTP service
 struct ChorusService {
     peer: HashedPeer,
+
+    /// The IP address of the peer we are connected to (not any address a PROXY protocol
+    /// header gave), if known
+    source: Option<IpAddr>,
+
+    /// Whether the listener this came in on is behind a proxy
+    behind_proxy: bool,
 }
 
 impl Service<Request<Incoming>> for ChorusService {
@@ -95,37 +136,51 @@ impl Service<Request<Incoming>> for ChorusService {
         let failvalue =
             |c: ChorusError| -> Self::Future { Box::pin(futures::future::ready(Err(c.into()))) };
 
-        if GLOBALS.config.read().chorus_is_behind_a_proxy {
-            // If chorus is behind a proxy that sets an "X-Real-Ip" header, we use
-            // that ip address instead (otherwise their log file will just give the proxy IP
-            // for every peer)
+        if self.behind_proxy {
+            // If chorus is behind a proxy that sets an "X-Real-Ip" (or "X-Forwarded-For" or
+            // "Forwarded", per proxy_ip_header) header, we use that ip address instead
+            // (otherwise their log file will just give the proxy IP for every peer)
             //
-            // This header must be found and be valid for us to proceed
-            if let Some(rip) = req.headers().get("x-real-ip") {
-                if let Ok(ripstr) = rip.to_str() {
-                    if let Ok(ipaddr) = ripstr.parse::<IpAddr>() {
-                        let hashed_ip = HashedIp::new(ipaddr);
-                        hashed_peer = HashedPeer::from_parts(hashed_ip, hashed_peer.port());
-                    } else {
-                        return failvalue(ChorusError::BadRealIpHeader(ripstr.to_owned()));
-                    }
-                } else {
-                    return failvalue(ChorusError::BadRealIpHeaderCharacters);
+            // Only trusted proxies may set these headers, so if none are configured no
+            // source is trusted. Peers on Unix domain sockets are local and trusted. From
+            // trusted sources the header must be found and be valid for us to proceed.
+            let (trusted_proxies, proxy_ip_header) = {
+                let config = GLOBALS.config.read();
+                (config.trusted_proxies.clone(), config.proxy_ip_header)
+            };
+            let ipaddr = match self.source {
+                Some(source) if !forwarded::is_trusted_proxy(source, &trusted_proxies) => {
+                    log::debug!(target: "Client", "{}: Ignoring proxy headers from untrusted source", hashed_peer);
+                    source
                 }
-            } else {
-                return failvalue(ChorusError::RealIpHeaderMissing);
+                _ => match forwarded::client_ip(req.headers(), proxy_ip_header, &trusted_proxies) {
+                    Ok(ipaddr) => ipaddr,
+                    Err(e) => return failvalue(e.inner),
+                },
+            };
+
+            // Manual blocks apply even if enable_ip_blocking is off
+            match crate::is_ip_blocked(ipaddr) {
+                Ok(false) => {}
+                Ok(true) => {
+                    log::debug!(target: "Client", "{}: Blocked by moderator", HashedIp::new(ipaddr));
+                    return failvalue(ChorusError::BlockedIp);
+                }
+                Err(e) => return failvalue(e.inner),
+            }
+            hashed_peer = HashedPeer::new(SocketAddr::new(ipaddr, hashed_peer.port()));
+            if let Err(e) = crate::carry_forward_ip_data(ipaddr) {
+                log::error!(target: "Client", "{}: {}", hashed_peer.ip(), e);
             }
 
             // Possibly IP block late (if behind a proxy)
             if GLOBALS.config.read().enable_ip_blocking {
-                if let Ok(ip_data) = crate::get_ip_data(hashed_peer.ip()) {
-                    if ip_data.is_banned() {
-                        log::debug!(target: "Client",
-                                    "{}: Blocking reconnection until {}",
-                                    hashed_peer.ip(),
-                                    ip_data.ban_until);
-                        return failvalue(ChorusError::BlockedIp);
-                    }
+                if let Ok(Some(ban_until)) = crate::peer_ban_until(hashed_peer) {
+                    log::debug!(target: "Client",
+                                "{}: Blocking reconnection until {}",
+                                hashed_peer.ip(),
+                                ban_until);
+                    return failvalue(ChorusError::BlockedIp);
                 }
             }
         }
@@ -149,15 +204,31 @@ async fn handle_http_request(
     };
 
     let max_conn = GLOBALS.config.read().max_connections_per_ip;
-    if let Some(cur) = GLOBALS.num_connections_per_ip.get(&peer.ip()) {
-        if *cur.value() >= max_conn {
-            return Ok(Response::builder()
-                .status(StatusCode::TOO_MANY_REQUESTS)
-                .body(Empty::new().map_err(|e| e.into()).boxed())?);
-        }
+    let max_subnet_conn = GLOBALS.config.read().max_connections_per_subnet;
+    let ip_full = GLOBALS
+        .num_connections_per_ip
+        .get(&peer.ip())
+        .is_some_and(|cur| *cur.value() >= max_conn);
+    let subnet_full = peer.subnet().is_some_and(|subnet| {
+        GLOBALS
+            .num_connections_per_subnet
+            .get(&subnet)
+            .is_some_and(|cur| *cur.value() >= max_subnet_conn)
+    });
+    if ip_full || subnet_full {
+        return Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
     }
 
-    if hyper_tungstenite::is_upgrade_request(&request) {
+    // Over HTTP/2, websockets are opened with an extended CONNECT (RFC 8441)
+    let websocket_over_h2 = request.method() == hyper::Method::CONNECT
+        && request
+            .extensions()
+            .get::<hyper::ext::Protocol>()
+            .is_some_and(|p| p.as_str().eq_ignore_ascii_case("websocket"));
+
+    if websocket_over_h2 || hyper_tungstenite::is_upgrade_request(&request) {
         // If the client asks for a Sec-Websocket-Protocol that we don't understand,
         // Respond with 501 Not Implemented
         let maybe_protocol: Option<String> = match request.headers().get("sec-websocket-protocol") {
@@ -184,8 +255,55 @@ async fn handle_http_request(
         web_socket_config.max_message_size = Some(1024 * 1024); // 1 MB
         web_socket_config.max_frame_size = Some(1024 * 1024); // 1 MB
 
-        let (mut response, websocket) =
-            hyper_tungstenite::upgrade(&mut request, Some(web_socket_config))?;
+        if request
+            .headers()
+            .get("sec-websocket-version")
+            .map(|v| v.as_bytes())
+            != Some(b"13")
+        {
+            return Err(ProtocolError::MissingSecWebSocketVersionHeader.into());
+        }
+        let mut response = if websocket_over_h2 {
+            // The stream is ours once we accept; there is no key to answer
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Empty::new().map_err(|e| e.into()).boxed())?
+        } else {
+            let key = request
+                .headers()
+                .get("sec-websocket-key")
+                .ok_or(ProtocolError::MissingSecWebSocketKey)?;
+            Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(http::header::CONNECTION, "upgrade")
+                .header(http::header::UPGRADE, "websocket")
+                .header(
+                    http::header::SEC_WEBSOCKET_ACCEPT,
+                    derive_accept_key(key.as_bytes()),
+                )
+                .body(Empty::new().map_err(|e| e.into()).boxed())?
+        };
+
+        // Compress messages if the client offers to
+        let (compression, window_bits) = {
+            let config = GLOBALS.config.read();
+            (
+                config.websocket_compression,
+                config.websocket_compression_window_bits,
+            )
+        };
+        let deflate = if compression {
+            deflate::negotiate(request.headers(), window_bits)
+        } else {
+            None
+        };
+        if let Some(params) = deflate {
+            response.headers_mut().insert(
+                http::header::SEC_WEBSOCKET_EXTENSIONS,
+                http::header::HeaderValue::from_str(&params.response_header())
+                    .expect("extension parameters are ASCII"),
+            );
+        }
 
         // If the client asked for Sec-Websocket-Protocol, then we already checked it must
         // have asked for 'nostr', so send that as a response header
@@ -197,18 +315,39 @@ async fn handle_http_request(
         }
 
         // Start the websocket thread
-        tokio::spawn(async move { websocket_thread(peer, websocket, origin, ua).await });
+        let upgrade = hyper::upgrade::on(&mut request);
+        tokio::spawn(async move {
+            websocket_thread(peer, upgrade, deflate, web_socket_config, origin, ua).await
+        });
 
-        Ok(response.map(|body| body.map_err(|e| e.into()).boxed()))
+        Ok(response)
     } else {
         web::serve_http(peer, request).await
     }
 }
 
-async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: String, ua: String) {
+async fn websocket_thread(
+    peer: HashedPeer,
+    upgrade: OnUpgrade,
+    deflate: Option<DeflateParams>,
+    web_socket_config: WebSocketConfig,
+    origin: String,
+    ua: String,
+) {
     // Await the websocket upgrade process
-    match websocket.await {
-        Ok(websocket) => {
+    match upgrade.await {
+        Ok(upgraded) => {
+            // Count bytes both before and after compression
+            let io = CountingStream::uncompressed(DeflateStream::new(
+                TokioIo::new(upgraded),
+                deflate,
+                GLOBALS.config.read().websocket_compression_min_bytes,
+                web_socket_config.max_message_size.unwrap_or(usize::MAX),
+            ));
+            let websocket =
+                WebSocketStream::from_raw_socket(io, Role::Server, Some(web_socket_config)).await;
+            let session = session::register_session(peer, origin.clone(), ua.clone());
+
             // Build a websocket service
             let mut ws_service = WebSocketService {
                 peer,
@@ -224,10 +363,15 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                 error_punishment: 0.0,
                 replied: false,
                 negentropy_sub: None,
+                subid: None,
+                management_notifications: None,
+                session,
+                permissions: None,
             };
 
             // Increment connection count
             let old_num_websockets = GLOBALS.num_connections.fetch_add(1, Ordering::SeqCst);
+            GLOBALS.stats.connections.fetch_add(1, Ordering::Relaxed);
 
             // Increment per-ip connection count
             GLOBALS
@@ -235,11 +379,19 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                 .entry(peer.ip())
                 .and_modify(|count| *count += 1)
                 .or_insert(1);
+            if let Some(subnet) = peer.subnet() {
+                GLOBALS
+                    .num_connections_per_subnet
+                    .entry(subnet)
+                    .and_modify(|count| *count += 1)
+                    .or_insert(1);
+            }
 
             // we cheat somewhat and log these websocket open and close messages
             // as server messages
             log::info!(
                 target: "Server",
+                session = ws_service.session.id;
                 "{}: TOTAL={}, New Connection: {}, {}",
                 peer,
                 old_num_websockets + 1,
@@ -252,7 +404,13 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
             let mut msg = "Closed";
 
             // Handle the websocket
-            if let Err(e) = ws_service.handle_websocket_stream().await {
+            let result = logging::SESSION
+                .scope(
+                    ws_service.session.clone(),
+                    ws_service.handle_websocket_stream(),
+                )
+                .await;
+            if let Err(e) = result {
                 match e.inner {
                     ChorusError::Tungstenite(tungstenite::error::Error::Protocol(
                         tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
@@ -288,6 +446,9 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                         session_exit = SessionExit::Timeout;
                         msg = "Timed Out (with no subscriptions)";
                     }
+                    ChorusError::Kicked => {
+                        msg = "Kicked";
+                    }
                     ChorusError::Io(_) => {
                         // Usually "Connection reset by peer" but any I/O error
                         // isn't a big deal.
@@ -301,6 +462,8 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                 }
             }
 
+            session::unregister_session(ws_service.session.id);
+
             // Decrement connection count
             let old_num_websockets = GLOBALS.num_connections.fetch_sub(1, Ordering::SeqCst);
 
@@ -315,16 +478,34 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
                 }
                 None => unreachable!("The connection count should be greater than zero"),
             };
+            if let Some(subnet) = peer.subnet() {
+                if let Some(mut refmut) = GLOBALS.num_connections_per_subnet.get_mut(&subnet) {
+                    *refmut.value_mut() = refmut.value().saturating_sub(1);
+                }
+            }
 
             // Update ip data (including ban time)
             let minimum_ban_seconds = GLOBALS.config.read().minimum_ban_seconds;
+            let half_life_seconds = GLOBALS.config.read().ip_reputation_half_life_days * 86400;
             let ban_seconds = if GLOBALS.config.read().enable_ip_blocking {
                 let mut ban_seconds = 0;
                 if let Ok(mut ip_data) = get_ip_data(peer.ip()) {
-                    ban_seconds =
-                        ip_data.update_on_session_close(session_exit, minimum_ban_seconds);
+                    ban_seconds = ip_data.update_on_session_close(
+                        session_exit,
+                        minimum_ban_seconds,
+                        half_life_seconds,
+                    );
                     let _ = update_ip_data(peer.ip(), &ip_data);
                 }
+
+                // The subnet shares in the reputation. It is only banned for errors,
+                // so that its other addresses can reconnect right away.
+                if let Some(subnet) = peer.subnet() {
+                    if let Ok(mut subnet_data) = get_ip_data(subnet) {
+                        subnet_data.update_on_session_close(session_exit, 0, half_life_seconds);
+                        let _ = update_ip_data(subnet, &subnet_data);
+                    }
+                }
                 ban_seconds
             } else {
                 minimum_ban_seconds
@@ -334,6 +515,7 @@ async fn websocket_thread(peer: HashedPeer, websocket: HyperWebsocket, origin: S
             // as server messages
             log::info!(
                 target: "Server",
+                session = ws_service.session.id;
                 "{}: TOTAL={}, {}, ban={}s",
                 peer,
                 old_num_websockets - 1,
@@ -352,7 +534,7 @@ struct WebSocketService {
     pub subscriptions: HashMap<String, Vec<OwnedFilter>>,
     pub neg_subscriptions: HashMap<String, NegentropyStorageVector>,
     pub buffer: Vec<u8>,
-    pub websocket: WebSocketStream<TokioIo<Upgraded>>,
+    pub websocket: WebSocketStream<CountingStream<DeflateStream<TokioIo<Upgraded>>>>,
     pub last_message: Instant,
     pub burst_tokens: usize,
     pub challenge: String,
@@ -360,9 +542,32 @@ struct WebSocketService {
     pub error_punishment: f32,
     pub replied: bool,
     pub negentropy_sub: Option<String>,
+    pub subid: Option<String>,
+    pub management_notifications: Option<BroadcastReceiver<String>>,
+    pub session: Arc<Session>,
+
+    /// The permissions of the user, and the permissions generation they were computed at
+    pub permissions: Option<(u64, PermissionSet)>,
 }
 
 impl WebSocketService {
+    /// The permissions of the authenticated user, cached until users or roles change
+    fn permissions(&mut self) -> PermissionSet {
+        let user = match self.user {
+            Some(user) => user,
+            None => return PermissionSet::default(),
+        };
+        let generation = GLOBALS.permissions_generation.load(Ordering::Relaxed);
+        match self.permissions {
+            Some((computed_at, permissions)) if computed_at == generation => permissions,
+            _ => {
+                let permissions = get_permissions(user);
+                self.permissions = Some((generation, permissions));
+                permissions
+            }
+        }
+    }
+
     async fn send(&mut self, m: Message) -> Result<(), Error> {
         log::trace!(target: "Client", "{}: {}", self.peer, m);
 
@@ -379,6 +584,19 @@ impl WebSocketService {
         }
 
         self.replied = true;
+        self.session
+            .bytes_outbound
+            .fetch_add(m.len() as u64, Ordering::Relaxed);
+        Ok(self.websocket.send(m).await?)
+    }
+
+    // Send a message we push to them unasked. It counts toward their outbound bytes
+    // but, unlike replies, not toward throttling.
+    async fn push(&mut self, m: Message) -> Result<(), Error> {
+        log::trace!(target: "Client", "{}: {}", self.peer, m);
+        self.session
+            .bytes_outbound
+            .fetch_add(m.len() as u64, Ordering::Relaxed);
         Ok(self.websocket.send(m).await?)
     }
 
@@ -392,6 +610,7 @@ impl WebSocketService {
             ChorusError::BannedUser | ChorusError::BlockedIp => {
                 (CloseCode::Policy, Utf8Bytes::from_static("banned"))
             }
+            ChorusError::Kicked => (CloseCode::Policy, Utf8Bytes::from_static("kicked")),
             e => (CloseCode::Error, format!("{}", e).into()),
         };
 
@@ -425,11 +644,20 @@ impl WebSocketService {
         let _ = interval.tick().await; // consume the first tick
         tokio::pin!(interval);
 
+        let mut stats_interval = tokio::time::interval(Duration::from_secs(10));
+        let _ = stats_interval.tick().await; // consume the first tick
+        tokio::pin!(stats_interval);
+
+        let session = self.session.clone();
+
         loop {
             tokio::select! {
                 instant = interval.tick() => {
                     // Drop them if they have no subscriptions
-                    if self.subscriptions.is_empty() && self.neg_subscriptions.is_empty() {
+                    if self.subscriptions.is_empty()
+                        && self.neg_subscriptions.is_empty()
+                        && self.management_notifications.is_none()
+                    {
                         // And they are idle for timeout_seconds with no subscriptions
                         if last_message_at + Duration::from_secs(timeout_seconds) < instant {
                             self.wsclose(ChorusError::TimedOut.into()).await?;
@@ -444,6 +672,7 @@ impl WebSocketService {
                             if let Err(e) = self.handle_websocket_message(message).await {
                                 self.wsclose(e).await?;
                             }
+                            self.update_session();
                         },
                         None => break, // the websocket is closed
                     }
@@ -452,6 +681,31 @@ impl WebSocketService {
                     let offset = offset_result?;
                     self.handle_new_event(offset).await?;
                 },
+                _ = stats_interval.tick() => {
+                    // Send stats to moderators watching, while they still may
+                    if self.management_notifications.is_some() && !self.permissions().can_manage() {
+                        self.management_notifications = None;
+                    }
+                    if self.management_notifications.is_some() {
+                        let stats = crate::web::management::stats_notification();
+                        self.push(Message::text(stats)).await?;
+                    }
+                },
+                notification = recv_notification(&mut self.management_notifications) => {
+                    match notification {
+                        Ok(json) if self.permissions().can_manage() => {
+                            self.push(Message::text(json)).await?
+                        }
+                        Ok(_) => self.management_notifications = None,
+                        Err(RecvError::Lagged(n)) => {
+                            log::warn!(target: "Client", "{}: Dropped {} management notifications", self.peer, n);
+                        }
+                        Err(RecvError::Closed) => self.management_notifications = None,
+                    }
+                },
+                _ = session.kicked() => {
+                    self.wsclose(ChorusError::Kicked.into()).await?;
+                },
                 _r = shutting_down.changed() => {
                     self.wsclose(ChorusError::ShuttingDown.into()).await?;
                 },
@@ -461,6 +715,17 @@ impl WebSocketService {
         Ok(())
     }
 
+    // Publish what the session registry shows about us
+    fn update_session(&self) {
+        *self.session.user.write() = self.user;
+        self.session
+            .subscriptions
+            .store(self.subscriptions.len(), Ordering::Relaxed);
+        self.session
+            .neg_subscriptions
+            .store(self.neg_subscriptions.len(), Ordering::Relaxed);
+    }
+
     // If the event matches a subscription they have open, send them the event
     async fn handle_new_event(&mut self, new_event_offset: u64) -> Result<(), Error> {
         if self.subscriptions.is_empty() {
@@ -474,7 +739,7 @@ impl WebSocketService {
             .get_event_by_offset(new_event_offset)?;
 
         let event_flags = nostr::event_flags(event, &self.user);
-        let authorized_user = self.user.map(is_authorized_user).unwrap_or(false);
+        let authorized_user = self.permissions().contains(Permission::ReadPrivate);
 
         'subs: for (subid, filters) in self.subscriptions.iter() {
             for filter in filters.iter() {
@@ -487,10 +752,12 @@ impl WebSocketService {
                         //       the post-EOSE data
                     } else if screen_result == ScreenResult::Match {
                         let message = NostrReply::Event(subid, event);
+                        let message = Message::text(message.as_json()?);
+                        self.session
+                            .bytes_outbound
+                            .fetch_add(message.len() as u64, Ordering::Relaxed);
                         // note, this is not currently counted in throttling
-                        self.websocket
-                            .send(Message::text(message.as_json()?))
-                            .await?;
+                        self.websocket.send(message).await?;
                         continue 'subs;
                     }
                 }
@@ -508,6 +775,10 @@ impl WebSocketService {
                 (config.throttling_burst, config.throttling_bytes_per_second)
             };
 
+            self.session
+                .bytes_inbound
+                .fetch_add(message.len() as u64, Ordering::Relaxed);
+
             // Get (and update) timing
             let elapsed = self.last_message.elapsed();
             self.last_message = Instant::now();
@@ -609,6 +880,16 @@ impl WebSocketService {
     }
 }
 
+// Receive the next management notification, or wait forever if not listening for them
+async fn recv_notification(
+    receiver: &mut Option<BroadcastReceiver<String>>,
+) -> Result<String, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 /// Print statistics
 pub fn print_stats() {
     let mut runtime: u64 = GLOBALS.start_time.elapsed().as_secs();
@@ -631,6 +912,12 @@ pub fn print_stats() {
         GLOBALS.bytes_outbound.load(Ordering::Relaxed),
         (GLOBALS.bytes_outbound.load(Ordering::Relaxed) as f32) / (runtime as f32)
     );
+    log::info!(
+        target: "Server",
+        "Websocket uncompressed: {} bytes in, {} bytes out",
+        GLOBALS.bytes_inbound_uncompressed.load(Ordering::Relaxed),
+        GLOBALS.bytes_outbound_uncompressed.load(Ordering::Relaxed)
+    );
     if let Ok(status) = GLOBALS.store.get().unwrap().stats() {
         log::info!(
             target: "Server",
@@ -640,6 +927,34 @@ pub fn print_stats() {
             status.index_stats.disk_usage
         );
     }
+
+    let events = GLOBALS.stats.event_report();
+    log::info!(
+        target: "Server",
+        "Events by result: {}",
+        format_counts(events.by_result.iter())
+    );
+    log::info!(
+        target: "Server",
+        "Events by error: {}",
+        format_counts(events.by_error.iter())
+    );
+    for (kind, results) in events.by_kind.iter() {
+        log::info!(
+            target: "Server",
+            "Events of kind {}: {}",
+            kind,
+            format_counts(results.iter())
+        );
+    }
+}
+
+// Format counters as "name=count, name=count"
+fn format_counts<'a>(counts: impl Iterator<Item = (&'a &'static str, &'a u64)>) -> String {
+    counts
+        .map(|(name, count)| format!("{name}={count}"))
+        .collect::<Vec<String>>()
+        .join(", ")
 }
 
 /// Load config file
@@ -653,24 +968,167 @@ pub fn load_config<P: AsRef<Path>>(config_path: P) -> Result<Config, Error> {
     Ok(config)
 }
 
+/// Reload the config file, rebuilding the relay information document and reloading
+/// TLS certificates. If the file cannot be loaded the running config is kept.
+/// Returns the names of changed settings that only take effect after a restart.
+pub fn reload_config<P: AsRef<Path>>(config_path: P) -> Result<Vec<&'static str>, Error> {
+    let mut config = load_config(config_path)?;
+
+    let restart_required = GLOBALS.config.read().keep_restart_required(&mut config);
+    for name in restart_required.iter() {
+        log::warn!(target: "Server", "Config setting {} changed but requires a restart", name);
+    }
+
+    // Reload certificates if we are using TLS. On failure keep the old ones.
+    if GLOBALS.tls_acceptor.read().is_some() {
+        match tls::tls_acceptor(&config) {
+            Ok(acceptor) => {
+                *GLOBALS.tls_acceptor.write() = Some(acceptor);
+                log::info!(target: "Server", "Reloaded TLS certificates");
+            }
+            Err(e) => {
+                log::error!(target: "Server", "Could not reload TLS certificates, keeping the old ones: {}", e);
+            }
+        }
+    }
+
+    *GLOBALS.config.write() = config;
+
+    // Rebuild the relay information document on next use
+    *GLOBALS.rid.write() = None;
+
+    // Admins in the config file may have changed
+    permissions_changed();
+
+    Ok(restart_required)
+}
+
 /// Setup logging
 pub fn setup_logging(config: &Config) {
-    env_logger::Builder::new()
-        .filter_level(config.library_log_level)
-        .filter(Some("Server"), config.server_log_level)
-        .filter(Some("Client"), config.client_log_level)
-        .format_target(true)
-        .format_module_path(false)
-        .format_timestamp_millis()
-        .init();
-
-    log::debug!(target: "Server", "Loaded config file.");
+    logging::setup_logging(config);
 }
 
 /// Setup storage
 pub fn setup_store(config: &Config) -> Result<(), Error> {
     let store = setup_store_and_return(config)?;
+    index_blob_uploaders(&store)?;
     let _ = GLOBALS.store.set(store);
+    load_ip_hash_keys(config)?;
+    load_blocked_prefix_lens()?;
+    Ok(())
+}
+
+// Where the IP hash keys are kept
+fn ip_hash_keys_path(config: &Config) -> std::path::PathBuf {
+    Path::new(&config.data_directory).join("ip_hash_keys")
+}
+
+/// Load the keys used to hash IP addresses, creating the first key if there are none.
+/// Records made before keys existed used the legacy unkeyed hash, which is kept as the
+/// previous key so that their reputation carries forward.
+pub fn load_ip_hash_keys(config: &Config) -> Result<(), Error> {
+    let path = ip_hash_keys_path(config);
+    let keys = match ip::read_ip_hash_keys(&path)? {
+        Some(keys) if !keys.is_empty() => keys,
+        _ => {
+            let keys = vec![IpHashKey::generate()?, IpHashKey::LEGACY];
+            ip::write_ip_hash_keys(&path, &keys)?;
+            keys
+        }
+    };
+    *GLOBALS.ip_hash_keys.write() = keys;
+    Ok(())
+}
+
+// How many reputation half lives after the first IP hash key replaced the legacy hash
+// we still look for reputation under the legacy hash
+const LEGACY_CARRY_FORWARD_HALF_LIVES: u64 = 10;
+
+/// Rotate the IP hash key if it is older than ip_hash_key_rotation_days. The previous
+/// key is kept so that reputation carries forward. Older keys are kept only while a
+/// manual block that may have been hashed with them remains.
+pub fn rotate_ip_hash_key(config: &Config) -> Result<(), Error> {
+    if config.ip_hash_key_rotation_days == 0 {
+        return Ok(());
+    }
+    let mut keys = ip::ip_hash_keys();
+    let due = keys[0].key.is_none()
+        || Time::now().as_u64() >= keys[0].created_at + config.ip_hash_key_rotation_days * 86400;
+    if !due {
+        return Ok(());
+    }
+
+    keys.insert(0, IpHashKey::generate()?);
+    let blocks = dump_ip_blocks()?;
+    let mut retained: Vec<IpHashKey> = keys[..2].to_vec();
+    for key in keys[2..].iter() {
+        if blocks.iter().any(|(_, block)| match block.key_created_at {
+            Some(created_at) => created_at == key.created_at,
+            None => block.created_at >= key.created_at,
+        }) {
+            retained.push(*key);
+        }
+    }
+
+    ip::write_ip_hash_keys(&ip_hash_keys_path(config), &retained)?;
+    *GLOBALS.ip_hash_keys.write() = retained;
+    log::info!(target: "Server", "Rotated the IP hash key");
+    Ok(())
+}
+
+/// Move the IpData of an IP address (and of its subnet) from its hash under the
+/// previous key to its hash under the current key, unless it already has data under
+/// the current key. This is skipped if the previous key is the legacy hash and was
+/// replaced long enough ago for any reputation under it to have decayed away.
+pub fn carry_forward_ip_data(ip_addr: IpAddr) -> Result<(), Error> {
+    let keys = ip::ip_hash_keys();
+    if keys.len() < 2 {
+        return Ok(());
+    }
+    let half_life_seconds = GLOBALS.config.read().ip_reputation_half_life_days * 86400;
+    if keys[1].key.is_none()
+        && half_life_seconds > 0
+        && Time::now().as_u64()
+            >= keys[0].created_at + LEGACY_CARRY_FORWARD_HALF_LIVES * half_life_seconds
+    {
+        return Ok(());
+    }
+    carry_forward(
+        HashedIp::new_with_key(ip_addr, &keys[0]),
+        HashedIp::new_with_key(ip_addr, &keys[1]),
+    )?;
+    if let Some(subnet) = ip::subnet_of(ip_addr) {
+        carry_forward(
+            HashedIp::new_network_with_key(subnet, &keys[0]),
+            HashedIp::new_network_with_key(subnet, &keys[1]),
+        )?;
+    }
+    Ok(())
+}
+
+fn carry_forward(current: HashedIp, previous: HashedIp) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let ip_data = store
+        .extra_table("ip_data")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
+
+    // Most connections have nothing to move, so check before taking a write txn
+    {
+        let txn = store.read_txn()?;
+        if ip_data.get(&txn, &current.0)?.is_some() || ip_data.get(&txn, &previous.0)?.is_none() {
+            return Ok(());
+        }
+    }
+
+    let mut txn = store.write_txn()?;
+    if ip_data.get(&txn, &current.0)?.is_none() {
+        if let Some(bytes) = ip_data.get(&txn, &previous.0)? {
+            let bytes = bytes.to_owned();
+            ip_data.put(&mut txn, &current.0, &bytes)?;
+            ip_data.delete(&mut txn, &previous.0)?;
+        }
+    }
+    txn.commit()?;
     Ok(())
 }
 
@@ -679,9 +1137,16 @@ pub fn setup_store_and_return(config: &Config) -> Result<Store, Error> {
     let store = Store::new(
         &config.data_directory,
         vec![
+            "admins",           // pubkey.as_slice() -> empty
             "approved-events",  // id.as_slice() -> u8(bool)
             "approved-pubkeys", // pubkey.as_slice() -> u8(bool)
+            "blob-uploaders",   // hash + pubkey.as_slice() -> empty (index of blob-uploads)
+            "blob-uploads",     // pubkey.as_slice() + hash -> u64(le) uploaded time
+            "blocked-ips",      // HashedIp.0 -> IpBlock
             "ip_data",          // HashedIp.0 -> IpData
+            "relay-info",       // field name -> utf8 value (overrides config)
+            "roles",            // role name -> u8(PermissionSet bits)
+            "user-roles",       // pubkey.as_slice() + role name -> empty
             "users",            // pubkey.as_slice() -> u8(bool) true if moderator
         ],
     )?;
@@ -700,7 +1165,19 @@ pub fn get_ip_data(ip: HashedIp) -> Result<IpData, Error> {
         Some(b) => b,
         None => return Ok(Default::default()),
     };
-    Ok(IpData::read_from_buffer(bytes)?)
+    IpData::from_bytes(bytes)
+}
+
+/// If the peer's IP or its subnet is banned, when the ban ends
+pub fn peer_ban_until(peer: HashedPeer) -> Result<Option<u64>, Error> {
+    let mut ban_until: Option<u64> = None;
+    for ip in std::iter::once(peer.ip()).chain(peer.subnet()) {
+        let ip_data = get_ip_data(ip)?;
+        if ip_data.is_banned() {
+            ban_until = ban_until.max(Some(ip_data.ban_until));
+        }
+    }
+    Ok(ban_until)
 }
 
 /// Get IpData in storage about this remote HashedIp
@@ -728,14 +1205,501 @@ pub fn dump_ip_data() -> Result<Vec<(HashedIp, IpData)>, Error> {
     for i in ip_data.iter(&txn)? {
         let (key, val) = i?;
         let hashedip = HashedIp::from_bytes(key);
-        let data = IpData::read_from_buffer(val)?;
+        let data = IpData::from_bytes(val)?;
         output.push((hashedip, data));
     }
     Ok(output)
 }
 
+/// Delete the IpData about this remote HashedIp, resetting its reputation and any ban
+pub fn delete_ip_data(ip: HashedIp) -> Result<bool, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let ip_data = store
+        .extra_table("ip_data")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
+    let mut txn = store.write_txn()?;
+    let deleted = ip_data.delete(&mut txn, &ip.0)?;
+    txn.commit()?;
+    Ok(deleted)
+}
+
+/// Remove IpData that is no longer of use: not banned, with a reputation that has
+/// decayed away. Returns how many records were removed.
+pub fn compact_ip_data(half_life_seconds: u64) -> Result<usize, Error> {
+    let now = Time::now().as_u64();
+    let stale: Vec<HashedIp> = dump_ip_data()?
+        .drain(..)
+        .filter(|(_, data)| data.is_stale(now, half_life_seconds))
+        .map(|(ip, _)| ip)
+        .collect();
+    if stale.is_empty() {
+        return Ok(0);
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let ip_data = store
+        .extra_table("ip_data")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("ip_data")))?;
+    // Sessions may have closed since we looked, so check each again as we delete it
+    let mut removed: usize = 0;
+    let mut txn = store.write_txn()?;
+    for ip in stale.iter() {
+        let still_stale = match ip_data.get(&txn, &ip.0)? {
+            Some(bytes) => IpData::from_bytes(bytes)?.is_stale(now, half_life_seconds),
+            None => false,
+        };
+        if still_stale {
+            ip_data.delete(&mut txn, &ip.0)?;
+            removed += 1;
+        }
+    }
+    txn.commit()?;
+    Ok(removed)
+}
+
+/// Get the IDs of all events authored by a pubkey
+pub fn get_event_ids_by_pubkey(pubkey: Pubkey) -> Result<Vec<Id>, Error> {
+    let mut tags_buffer: [u8; 128] = [0; 128];
+    let (_, tags) = Tags::from_json(b"[]", &mut tags_buffer)?;
+    let mut filter_buffer: [u8; 128] = [0; 128];
+    let filter = Filter::from_parts(
+        &[],
+        &[pubkey],
+        &[],
+        tags,
+        None,
+        None,
+        None,
+        &mut filter_buffer,
+    )?;
+    let (events, _redacted) =
+        GLOBALS
+            .store
+            .get()
+            .unwrap()
+            .find_events(filter, true, 0, 0, |_| ScreenResult::Match)?;
+    Ok(events.iter().map(|e| e.id()).collect())
+}
+
+type RawTable =
+    pocket_db::heed::Database<pocket_db::heed::types::Bytes, pocket_db::heed::types::Bytes>;
+
+// The blob-uploads table and its blob-uploaders index (keyed by hash first)
+fn blob_upload_tables(store: &Store) -> Result<(RawTable, RawTable), Error> {
+    let blob_uploads = store
+        .extra_table("blob-uploads")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "blob-uploads",
+        )))?;
+    let blob_uploaders = store
+        .extra_table("blob-uploaders")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+            "blob-uploaders",
+        )))?;
+    Ok((blob_uploads, blob_uploaders))
+}
+
+/// Record that a pubkey uploaded a blossom blob
+pub fn record_blob_upload(pubkey: Pubkey, hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let (blob_uploads, blob_uploaders) = blob_upload_tables(store)?;
+    let mut txn = store.write_txn()?;
+    let mut key: Vec<u8> = pubkey.as_slice().to_owned();
+    key.extend(hash.as_slice());
+    blob_uploads.put(&mut txn, &key, &Time::now().as_u64().to_le_bytes())?;
+    let mut key: Vec<u8> = hash.as_slice().to_owned();
+    key.extend(pubkey.as_slice());
+    blob_uploaders.put(&mut txn, &key, &[])?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Remove the record that a pubkey uploaded a blossom blob
+pub fn remove_blob_upload(pubkey: Pubkey, hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let (blob_uploads, blob_uploaders) = blob_upload_tables(store)?;
+    let mut txn = store.write_txn()?;
+    let mut key: Vec<u8> = pubkey.as_slice().to_owned();
+    key.extend(hash.as_slice());
+    let _ = blob_uploads.delete(&mut txn, &key)?;
+    let mut key: Vec<u8> = hash.as_slice().to_owned();
+    key.extend(pubkey.as_slice());
+    let _ = blob_uploaders.delete(&mut txn, &key)?;
+    txn.commit()?;
+    Ok(())
+}
+
+/// Remove the records of everybody who uploaded a blossom blob (when it is deleted)
+pub fn remove_blob_uploads_of_hash(hash: HashOutput) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let (blob_uploads, blob_uploaders) = blob_upload_tables(store)?;
+    let mut txn = store.write_txn()?;
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for i in blob_uploaders.prefix_iter(&txn, hash.as_slice())? {
+        let (key, _val) = i?;
+        keys.push(key.to_owned());
+    }
+    for key in keys.iter() {
+        let mut upload_key: Vec<u8> = key[32..64].to_owned();
+        upload_key.extend(&key[0..32]);
+        let _ = blob_uploads.delete(&mut txn, &upload_key)?;
+        let _ = blob_uploaders.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Get the hashes of all blossom blobs uploaded by a pubkey
+pub fn get_blob_uploads_by_pubkey(pubkey: Pubkey) -> Result<Vec<HashOutput>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let (blob_uploads, _) = blob_upload_tables(store)?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<HashOutput> = Vec::new();
+    for i in blob_uploads.prefix_iter(&txn, pubkey.as_slice())? {
+        let (key, _val) = i?;
+        output.push(HashOutput::from_bytes(key[32..64].try_into().unwrap()));
+    }
+    Ok(output)
+}
+
+/// Is a blossom blob recorded as uploaded by anybody other than this pubkey?
+pub fn blob_has_other_uploader(hash: HashOutput, pubkey: Pubkey) -> Result<bool, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let (_, blob_uploaders) = blob_upload_tables(store)?;
+    let txn = store.read_txn()?;
+    for i in blob_uploaders.prefix_iter(&txn, hash.as_slice())? {
+        let (key, _val) = i?;
+        if &key[32..64] != pubkey.as_slice() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Build the blob-uploaders index from blob-uploads, if it was made before the index
+fn index_blob_uploaders(store: &Store) -> Result<(), Error> {
+    let (blob_uploads, blob_uploaders) = blob_upload_tables(store)?;
+    let mut txn = store.write_txn()?;
+    if !blob_uploaders.is_empty(&txn)? || blob_uploads.is_empty(&txn)? {
+        return Ok(());
+    }
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for i in blob_uploads.iter(&txn)? {
+        let (key, _val) = i?;
+        let mut index_key: Vec<u8> = key[32..64].to_owned();
+        index_key.extend(&key[0..32]);
+        keys.push(index_key);
+    }
+    for key in keys.iter() {
+        blob_uploaders.put(&mut txn, key, &[])?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Manually block an IP address (or network) until it is unblocked
+pub fn block_ip(ip: HashedIp, block: &IpBlock) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let blocked_ips =
+        store
+            .extra_table("blocked-ips")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blocked-ips",
+            )))?;
+    let mut txn = store.write_txn()?;
+    let bytes = block.write_to_vec()?;
+    blocked_ips.put(&mut txn, &ip.0, &bytes)?;
+    txn.commit()?;
+    GLOBALS.blocked_prefix_lens.write().insert(block.prefix_len);
+    Ok(())
+}
+
+/// Remove a manual block of an IP address (or network)
+pub fn unblock_ip(ip: HashedIp) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let blocked_ips =
+        store
+            .extra_table("blocked-ips")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blocked-ips",
+            )))?;
+    let mut txn = store.write_txn()?;
+    blocked_ips.delete(&mut txn, &ip.0)?;
+    txn.commit()?;
+    load_blocked_prefix_lens()?;
+    Ok(())
+}
+
+/// Get the manual block of an IP address (or network), if any
+pub fn get_ip_block(ip: HashedIp) -> Result<Option<IpBlock>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let blocked_ips =
+        store
+            .extra_table("blocked-ips")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blocked-ips",
+            )))?;
+    let txn = store.read_txn()?;
+    match blocked_ips.get(&txn, &ip.0)? {
+        Some(bytes) => Ok(Some(IpBlock::read_from_buffer(bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Dump all manual IP blocks
+pub fn dump_ip_blocks() -> Result<Vec<(HashedIp, IpBlock)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let blocked_ips =
+        store
+            .extra_table("blocked-ips")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blocked-ips",
+            )))?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<(HashedIp, IpBlock)> = Vec::new();
+    for i in blocked_ips.iter(&txn)? {
+        let (key, val) = i?;
+        let hashedip = HashedIp::from_bytes(key);
+        let block = IpBlock::read_from_buffer(val)?;
+        output.push((hashedip, block));
+    }
+    Ok(output)
+}
+
+/// Reload the prefix lengths in use by manual IP blocks
+pub fn load_blocked_prefix_lens() -> Result<(), Error> {
+    let prefix_lens = dump_ip_blocks()?
+        .into_iter()
+        .map(|(_, block)| block.prefix_len)
+        .collect();
+    *GLOBALS.blocked_prefix_lens.write() = prefix_lens;
+    Ok(())
+}
+
+/// Is this IP address manually blocked, either directly or as part of a blocked network?
+pub fn is_ip_blocked(ip_addr: IpAddr) -> Result<bool, Error> {
+    let ip_addr = ip_addr.to_canonical();
+    let prefix_lens = GLOBALS.blocked_prefix_lens.read().clone();
+    if prefix_lens.is_empty() {
+        return Ok(false);
+    }
+
+    let store = GLOBALS.store.get().unwrap();
+    let blocked_ips =
+        store
+            .extra_table("blocked-ips")
+            .ok_or(Into::<Error>::into(ChorusError::MissingTable(
+                "blocked-ips",
+            )))?;
+    let txn = store.read_txn()?;
+
+    // Blocks may have been made under any of the retained keys
+    for key in ip::ip_hash_keys().iter() {
+        for prefix_len in prefix_lens.iter() {
+            let hashed_ip = match prefix_len {
+                None => HashedIp::new_with_key(ip_addr, key),
+                Some(len) => match IpCidr::new(ip_addr, *len) {
+                    Ok(cidr) => HashedIp::new_network_with_key(cidr, key),
+                    Err(_) => continue, // prefix length of the other address family
+                },
+            };
+            if blocked_ips.get(&txn, &hashed_ip.0)?.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// A moderation decision, as stored in the approved-events and approved-pubkeys tables.
+/// The first byte is the approval. Older records have nothing more.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Approval {
+    pub approved: bool,
+    pub created_at: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl Approval {
+    pub fn new(approved: bool, reason: &str) -> Approval {
+        Approval {
+            approved,
+            created_at: Some(Time::now().as_u64()),
+            reason: if reason.is_empty() {
+                None
+            } else {
+                Some(reason.to_owned())
+            },
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Approval {
+        let approved = !bytes.is_empty() && bytes[0] != 0;
+        let created_at = bytes
+            .get(1..9)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()));
+        let reason = bytes
+            .get(9..)
+            .filter(|b| !b.is_empty())
+            .map(|b| String::from_utf8_lossy(b).into_owned());
+        Approval {
+            approved,
+            created_at,
+            reason,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![self.approved as u8];
+        if self.created_at.is_none() && self.reason.is_none() {
+            return bytes;
+        }
+        bytes.extend(self.created_at.unwrap_or(0).to_le_bytes());
+        if let Some(reason) = &self.reason {
+            bytes.extend(reason.as_bytes());
+        }
+        bytes
+    }
+}
+
+/// Which entries of a table to list, and how many
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter {
+    /// Only entries with keys after this one
+    pub after: Option<Vec<u8>>,
+
+    /// Only entries whose displayed key starts with this
+    pub prefix: Option<String>,
+
+    /// Only entries created at or after this time
+    pub since: Option<u64>,
+
+    /// Only entries whose reason contains this text
+    pub reason_contains: Option<String>,
+
+    /// At most this many entries
+    pub limit: Option<usize>,
+}
+
+impl ListFilter {
+    /// Does an entry match (ignoring `after` and `limit`)?
+    pub fn matches(&self, key: &str, created_at: Option<u64>, reason: Option<&str>) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if created_at.map(|c| c < since).unwrap_or(true) {
+                return false;
+            }
+        }
+        if let Some(text) = &self.reason_contains {
+            if !reason.map(|r| r.contains(text.as_str())).unwrap_or(false) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Walk a table in key order starting after `filter.after`, offering each entry to
+// `accept` until `filter.limit` entries have been accepted.
+fn scan_table<F>(table_name: &'static str, filter: &ListFilter, mut accept: F) -> Result<(), Error>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<bool, Error>,
+{
+    use std::ops::Bound;
+
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table(table_name)
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(table_name)))?;
+    let txn = store.read_txn()?;
+    let lower: Bound<&[u8]> = match &filter.after {
+        Some(after) => Bound::Excluded(after.as_slice()),
+        None => Bound::Unbounded,
+    };
+    let mut accepted: usize = 0;
+    for i in table.range(&txn, &(lower, Bound::Unbounded))? {
+        if filter.limit.map(|l| accepted >= l).unwrap_or(false) {
+            break;
+        }
+        let (key, val) = i?;
+        if accept(key, val)? {
+            accepted += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Remove events by id. For each id, whether it was found (and removed). The event
+/// store commits each removal on its own.
+pub fn remove_events(ids: &[Id]) -> Vec<Result<bool, Error>> {
+    let store = GLOBALS.store.get().unwrap();
+    ids.iter()
+        .map(|id| match store.get_event_by_id(*id)? {
+            Some(_) => {
+                store.remove_event(*id)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        })
+        .collect()
+}
+
+// Set (or clear, if `approval` is None) many approvals in one transaction
+fn batch_approval(
+    table_name: &'static str,
+    keys: &[&[u8]],
+    approval: Option<bool>,
+    reason: &str,
+) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let table = store
+        .extra_table(table_name)
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable(table_name)))?;
+    let mut txn = store.write_txn()?;
+    match approval {
+        Some(approval) => {
+            let bytes = Approval::new(approval, reason).to_bytes();
+            for key in keys.iter() {
+                table.put(&mut txn, key, &bytes)?;
+            }
+        }
+        None => {
+            for key in keys.iter() {
+                let _ = table.delete(&mut txn, key)?;
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Set (or clear, if `approval` is None) the approval of many events in one transaction
+pub fn batch_event_approval(ids: &[Id], approval: Option<bool>, reason: &str) -> Result<(), Error> {
+    let keys: Vec<&[u8]> = ids.iter().map(|id| id.as_slice()).collect();
+    batch_approval("approved-events", &keys, approval, reason)
+}
+
+/// Set (or clear, if `approval` is None) the approval of many pubkeys in one transaction
+pub fn batch_pubkey_approval(
+    pubkeys: &[Pubkey],
+    approval: Option<bool>,
+    reason: &str,
+) -> Result<(), Error> {
+    let keys: Vec<&[u8]> = pubkeys.iter().map(|pk| pk.as_slice()).collect();
+    batch_approval("approved-pubkeys", &keys, approval, reason)
+}
+
 /// Mark an event as approved or not
 pub fn mark_event_approval(id: Id, approval: bool) -> Result<(), Error> {
+    mark_event_approval_with_reason(id, approval, "")
+}
+
+/// Mark an event as approved or not, recording why
+pub fn mark_event_approval_with_reason(id: Id, approval: bool, reason: &str) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
     let approved_events = store
         .extra_table("approved-events")
@@ -743,7 +1707,8 @@ pub fn mark_event_approval(id: Id, approval: bool) -> Result<(), Error> {
             "approved-events",
         )))?;
     let mut txn = store.write_txn()?;
-    approved_events.put(&mut txn, id.as_slice(), &[approval as u8])?;
+    let bytes = Approval::new(approval, reason).to_bytes();
+    approved_events.put(&mut txn, id.as_slice(), &bytes)?;
     txn.commit()?;
     Ok(())
 }
@@ -795,8 +1760,40 @@ pub fn dump_event_approvals() -> Result<Vec<(Id, bool)>, Error> {
     Ok(output)
 }
 
+/// List event approval statuses of one kind (approved or banned)
+pub fn list_event_approvals(
+    approved: bool,
+    filter: &ListFilter,
+) -> Result<Vec<(Id, Approval)>, Error> {
+    let mut output: Vec<(Id, Approval)> = Vec::new();
+    scan_table("approved-events", filter, |key, val| {
+        let approval = Approval::from_bytes(val);
+        if approval.approved != approved
+            || !filter.matches(
+                &hex::encode(key),
+                approval.created_at,
+                approval.reason.as_deref(),
+            )
+        {
+            return Ok(false);
+        }
+        output.push((Id::from_bytes(key.try_into().unwrap()), approval));
+        Ok(true)
+    })?;
+    Ok(output)
+}
+
 /// Mark a pubkey as approved or not
 pub fn mark_pubkey_approval(pubkey: Pubkey, approval: bool) -> Result<(), Error> {
+    mark_pubkey_approval_with_reason(pubkey, approval, "")
+}
+
+/// Mark a pubkey as approved or not, recording why
+pub fn mark_pubkey_approval_with_reason(
+    pubkey: Pubkey,
+    approval: bool,
+    reason: &str,
+) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
     let approved_pubkeys = store
         .extra_table("approved-pubkeys")
@@ -804,7 +1801,8 @@ pub fn mark_pubkey_approval(pubkey: Pubkey, approval: bool) -> Result<(), Error>
             "approved-pubkeys",
         )))?;
     let mut txn = store.write_txn()?;
-    approved_pubkeys.put(&mut txn, pubkey.as_slice(), &[approval as u8])?;
+    let bytes = Approval::new(approval, reason).to_bytes();
+    approved_pubkeys.put(&mut txn, pubkey.as_slice(), &bytes)?;
     txn.commit()?;
     Ok(())
 }
@@ -856,6 +1854,46 @@ pub fn dump_pubkey_approvals() -> Result<Vec<(Pubkey, bool)>, Error> {
     Ok(output)
 }
 
+/// List pubkey approval statuses of one kind (approved or banned)
+pub fn list_pubkey_approvals(
+    approved: bool,
+    filter: &ListFilter,
+) -> Result<Vec<(Pubkey, Approval)>, Error> {
+    let mut output: Vec<(Pubkey, Approval)> = Vec::new();
+    scan_table("approved-pubkeys", filter, |key, val| {
+        let approval = Approval::from_bytes(val);
+        if approval.approved != approved
+            || !filter.matches(
+                &hex::encode(key),
+                approval.created_at,
+                approval.reason.as_deref(),
+            )
+        {
+            return Ok(false);
+        }
+        output.push((Pubkey::from_bytes(key.try_into().unwrap()), approval));
+        Ok(true)
+    })?;
+    Ok(output)
+}
+
+/// List authorized users (or only moderators)
+pub fn list_authorized_users(
+    moderators_only: bool,
+    filter: &ListFilter,
+) -> Result<Vec<(Pubkey, bool)>, Error> {
+    let mut output: Vec<(Pubkey, bool)> = Vec::new();
+    scan_table("users", filter, |key, val| {
+        let moderator: bool = !val.is_empty() && val[0] != 0;
+        if (moderators_only && !moderator) || !filter.matches(&hex::encode(key), None, None) {
+            return Ok(false);
+        }
+        output.push((Pubkey::from_bytes(key.try_into().unwrap()), moderator));
+        Ok(true)
+    })?;
+    Ok(output)
+}
+
 /// Add authorized user (or change moderator flag)
 pub fn add_authorized_user(pubkey: Pubkey, moderator: bool) -> Result<(), Error> {
     let store = GLOBALS.store.get().unwrap();
@@ -865,6 +1903,7 @@ pub fn add_authorized_user(pubkey: Pubkey, moderator: bool) -> Result<(), Error>
     let mut txn = store.write_txn()?;
     users.put(&mut txn, pubkey.as_slice(), &[moderator as u8])?;
     txn.commit()?;
+    permissions_changed();
     Ok(())
 }
 
@@ -877,6 +1916,7 @@ pub fn rm_authorized_user(pubkey: Pubkey) -> Result<(), Error> {
     let mut txn = store.write_txn()?;
     users.delete(&mut txn, pubkey.as_slice())?;
     txn.commit()?;
+    permissions_changed();
     Ok(())
 }
 
@@ -927,7 +1967,487 @@ pub fn is_moderator(pubkey: Pubkey) -> bool {
     }
 }
 
-/// Is the pubkey an admin?
-pub fn is_admin(pubkey: Pubkey) -> bool {
+/// Record that an authorization event was used, remembering it until `forget_after`
+/// (unixtime). Returns false if it was already used (a replay).
+pub fn use_auth_event(id: Id, forget_after: u64) -> bool {
+    // Forget expired ones at most once a minute
+    static LAST_PRUNED: AtomicU64 = AtomicU64::new(0);
+    let now = Time::now().as_u64();
+    let last_pruned = LAST_PRUNED.load(Ordering::Relaxed);
+    if now >= last_pruned + 60
+        && LAST_PRUNED
+            .compare_exchange(last_pruned, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        GLOBALS.seen_auth_events.retain(|_, until| *until >= now);
+    }
+
+    match GLOBALS.seen_auth_events.entry(id) {
+        dashmap::Entry::Occupied(_) => false,
+        dashmap::Entry::Vacant(entry) => {
+            entry.insert(forget_after);
+            true
+        }
+    }
+}
+
+/// Add an admin (in addition to the root admins in the config file)
+pub fn add_admin(pubkey: Pubkey) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let admins = store
+        .extra_table("admins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("admins")))?;
+    let mut txn = store.write_txn()?;
+    admins.put(&mut txn, pubkey.as_slice(), &[])?;
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Remove an admin (root admins in the config file are not affected)
+pub fn rm_admin(pubkey: Pubkey) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let admins = store
+        .extra_table("admins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("admins")))?;
+    let mut txn = store.write_txn()?;
+    let _ = admins.delete(&mut txn, pubkey.as_slice())?;
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Dump all admins from the database (not including root admins)
+pub fn dump_admins() -> Result<Vec<Pubkey>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let admins = store
+        .extra_table("admins")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("admins")))?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<Pubkey> = Vec::new();
+    for i in admins.iter(&txn)? {
+        let (key, _val) = i?;
+        output.push(Pubkey::from_bytes(key.try_into().unwrap()));
+    }
+    Ok(output)
+}
+
+/// Is the pubkey a root admin (from the config file)?
+pub fn is_root_admin(pubkey: Pubkey) -> bool {
     GLOBALS.config.read().admin_keys.contains(&pubkey)
 }
+
+/// Is the pubkey an admin? Database admins are cached until users, admins or roles
+/// change.
+pub fn is_admin(pubkey: Pubkey) -> bool {
+    if is_root_admin(pubkey) {
+        return true;
+    }
+
+    let generation = GLOBALS.permissions_generation.load(Ordering::Relaxed);
+    if let Some((read_at, admins)) = &*GLOBALS.admins.read() {
+        if *read_at == generation {
+            return admins.contains(&pubkey);
+        }
+    }
+
+    match dump_admins() {
+        Ok(admins) => {
+            let is_admin = admins.contains(&pubkey);
+            *GLOBALS.admins.write() = Some((generation, admins));
+            is_admin
+        }
+        Err(_) => false,
+    }
+}
+
+/// Define (or redefine) a custom role
+pub fn define_role(name: &str, permissions: PermissionSet) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let roles = store
+        .extra_table("roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("roles")))?;
+    let mut txn = store.write_txn()?;
+    roles.put(&mut txn, name.as_bytes(), &[permissions.bits()])?;
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Delete a custom role, revoking it from everybody who had it
+pub fn delete_role(name: &str) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let roles = store
+        .extra_table("roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("roles")))?;
+    let user_roles = store
+        .extra_table("user-roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("user-roles")))?;
+    let mut txn = store.write_txn()?;
+    let _ = roles.delete(&mut txn, name.as_bytes())?;
+    let mut keys: Vec<Vec<u8>> = Vec::new();
+    for i in user_roles.iter(&txn)? {
+        let (key, _val) = i?;
+        if &key[32..] == name.as_bytes() {
+            keys.push(key.to_owned());
+        }
+    }
+    for key in keys.iter() {
+        let _ = user_roles.delete(&mut txn, key)?;
+    }
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Get the permissions of a custom role
+pub fn get_role(name: &str) -> Result<Option<PermissionSet>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let roles = store
+        .extra_table("roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("roles")))?;
+    let txn = store.read_txn()?;
+    Ok(roles
+        .get(&txn, name.as_bytes())?
+        .map(|v| PermissionSet::from_bits(v.first().copied().unwrap_or(0))))
+}
+
+/// Dump all custom roles
+pub fn dump_roles() -> Result<Vec<(String, PermissionSet)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let roles = store
+        .extra_table("roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("roles")))?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<(String, PermissionSet)> = Vec::new();
+    for i in roles.iter(&txn)? {
+        let (key, val) = i?;
+        let name = String::from_utf8_lossy(key).into_owned();
+        let permissions = PermissionSet::from_bits(val.first().copied().unwrap_or(0));
+        output.push((name, permissions));
+    }
+    Ok(output)
+}
+
+/// Grant a custom role to a pubkey
+pub fn grant_role(pubkey: Pubkey, name: &str) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let user_roles = store
+        .extra_table("user-roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("user-roles")))?;
+    let mut txn = store.write_txn()?;
+    let mut key: Vec<u8> = pubkey.as_slice().to_owned();
+    key.extend(name.as_bytes());
+    user_roles.put(&mut txn, &key, &[])?;
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Revoke a custom role from a pubkey
+pub fn revoke_role(pubkey: Pubkey, name: &str) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let user_roles = store
+        .extra_table("user-roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("user-roles")))?;
+    let mut txn = store.write_txn()?;
+    let mut key: Vec<u8> = pubkey.as_slice().to_owned();
+    key.extend(name.as_bytes());
+    let _ = user_roles.delete(&mut txn, &key)?;
+    txn.commit()?;
+    permissions_changed();
+    Ok(())
+}
+
+/// Get the custom roles granted to a pubkey
+pub fn get_user_roles(pubkey: Pubkey) -> Result<Vec<String>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let user_roles = store
+        .extra_table("user-roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("user-roles")))?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<String> = Vec::new();
+    for i in user_roles.prefix_iter(&txn, pubkey.as_slice())? {
+        let (key, _val) = i?;
+        output.push(String::from_utf8_lossy(&key[32..]).into_owned());
+    }
+    Ok(output)
+}
+
+/// Dump all custom role grants
+pub fn dump_user_roles() -> Result<Vec<(Pubkey, String)>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let user_roles = store
+        .extra_table("user-roles")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("user-roles")))?;
+    let txn = store.read_txn()?;
+    let mut output: Vec<(Pubkey, String)> = Vec::new();
+    for i in user_roles.iter(&txn)? {
+        let (key, _val) = i?;
+        let pubkey = Pubkey::from_bytes(key[0..32].try_into().unwrap());
+        output.push((pubkey, String::from_utf8_lossy(&key[32..]).into_owned()));
+    }
+    Ok(output)
+}
+
+/// Note that users, admins or roles changed, so that cached permissions are refreshed
+pub fn permissions_changed() {
+    GLOBALS
+        .permissions_generation
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// All the permissions a pubkey has, from built-in and custom roles
+pub fn get_permissions(pubkey: Pubkey) -> PermissionSet {
+    if is_admin(pubkey) {
+        return roles::builtin_role("admin").unwrap_or_default();
+    }
+
+    let mut permissions = match get_authorized_user(pubkey) {
+        Ok(Some(true)) => roles::builtin_role("moderator").unwrap_or_default(),
+        Ok(Some(false)) => roles::builtin_role("user").unwrap_or_default(),
+        _ => PermissionSet::default(),
+    };
+
+    if let Ok(names) = get_user_roles(pubkey) {
+        for name in names.iter() {
+            if let Ok(Some(role)) = get_role(name) {
+                permissions = permissions.union(role);
+            }
+        }
+    }
+
+    permissions
+}
+
+/// Does the pubkey have the permission?
+pub fn has_permission(pubkey: Pubkey, permission: Permission) -> bool {
+    get_permissions(pubkey).contains(permission)
+}
+
+/// May the pubkey use the management API at all?
+pub fn can_manage(pubkey: Pubkey) -> bool {
+    get_permissions(pubkey).can_manage()
+}
+
+/// Set a relay information field, overriding the config file.
+/// An empty value removes the override.
+pub fn set_relay_info(field: &str, value: &str) -> Result<(), Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let relay_info = store
+        .extra_table("relay-info")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("relay-info")))?;
+    let mut txn = store.write_txn()?;
+    if value.is_empty() {
+        let _ = relay_info.delete(&mut txn, field.as_bytes())?;
+    } else {
+        relay_info.put(&mut txn, field.as_bytes(), value.as_bytes())?;
+    }
+    txn.commit()?;
+
+    // Regenerate the relay information document next time it is served
+    *GLOBALS.rid.write() = None;
+
+    Ok(())
+}
+
+/// Get a relay information field override
+pub fn get_relay_info(field: &str) -> Result<Option<String>, Error> {
+    let store = GLOBALS.store.get().unwrap();
+    let relay_info = store
+        .extra_table("relay-info")
+        .ok_or(Into::<Error>::into(ChorusError::MissingTable("relay-info")))?;
+    let txn = store.read_txn()?;
+    Ok(relay_info
+        .get(&txn, field.as_bytes())?
+        .map(|v| String::from_utf8_lossy(v).into_owned()))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    /// Set up a store in a fresh temporary directory, once per test run. The store
+    /// is shared, so tests using it run one at a time while holding the guard.
+    pub(crate) fn setup_test_store() -> MutexGuard<'static, ()> {
+        static SETUP: Once = Once::new();
+        static LOCK: Mutex<()> = Mutex::new(());
+        SETUP.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("chorus-test-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let config = Config {
+                data_directory: dir.to_string_lossy().into_owned(),
+                ..Default::default()
+            };
+            setup_store(&config).unwrap();
+        });
+        LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn test_blob_uploads() {
+        let _guard = setup_test_store();
+
+        let alice = Pubkey::read_hex(&[b'a'; 64]).unwrap();
+        let bob = Pubkey::read_hex(&[b'b'; 64]).unwrap();
+        let shared = HashOutput::from_bytes([1; 32]);
+        let own = HashOutput::from_bytes([2; 32]);
+
+        record_blob_upload(alice, shared).unwrap();
+        record_blob_upload(bob, shared).unwrap();
+        record_blob_upload(alice, own).unwrap();
+        assert_eq!(
+            get_blob_uploads_by_pubkey(alice).unwrap(),
+            vec![shared, own]
+        );
+        assert!(blob_has_other_uploader(shared, alice).unwrap());
+        assert!(!blob_has_other_uploader(own, alice).unwrap());
+
+        remove_blob_upload(bob, shared).unwrap();
+        assert!(!blob_has_other_uploader(shared, alice).unwrap());
+
+        // Deleting a blob forgets all of its uploaders
+        remove_blob_uploads_of_hash(shared).unwrap();
+        assert_eq!(get_blob_uploads_by_pubkey(alice).unwrap(), vec![own]);
+    }
+
+    #[test]
+    fn test_use_auth_event() {
+        let id = Id::read_hex(&[b'e'; 64]).unwrap();
+        let now = Time::now().as_u64();
+        assert!(use_auth_event(id, now + 60));
+        assert!(!use_auth_event(id, now + 60));
+    }
+
+    #[test]
+    fn test_is_admin_cache() {
+        let _guard = setup_test_store();
+
+        let carol = Pubkey::read_hex(&[b'7'; 64]).unwrap();
+        assert!(!is_admin(carol));
+        add_admin(carol).unwrap();
+        assert!(is_admin(carol));
+        rm_admin(carol).unwrap();
+        assert!(!is_admin(carol));
+    }
+
+    #[test]
+    fn test_rotate_ip_hash_key_retains_block_keys() {
+        let _guard = setup_test_store();
+        let config = Config {
+            data_directory: std::env::temp_dir()
+                .join(format!("chorus-test-{}", std::process::id()))
+                .to_string_lossy()
+                .into_owned(),
+            ip_hash_key_rotation_days: 1,
+            ..Default::default()
+        };
+
+        let original = ip::ip_hash_keys();
+        let key = |created_at: u64, byte: u8| IpHashKey {
+            created_at,
+            key: Some([byte; 32]),
+        };
+        let due = Time::now().as_u64() - 2 * 86400;
+        *GLOBALS.ip_hash_keys.write() = vec![key(due, 1), key(1000, 2), key(500, 3), key(100, 4)];
+
+        // One block hashed with the key made at 500, and one given as a hash at 200,
+        // which can only have been made with the key made at 100
+        let hashed = HashedIp::new_with_key("10.0.0.1".parse().unwrap(), &key(500, 3));
+        let tagged = HashedIp::new_with_key("10.0.0.2".parse().unwrap(), &key(100, 4));
+        let block = |created_at: u64, key_created_at: Option<u64>| IpBlock {
+            reason: String::new(),
+            created_at,
+            prefix_len: None,
+            key_created_at,
+        };
+        block_ip(hashed, &block(600, Some(500))).unwrap();
+        block_ip(tagged, &block(200, None)).unwrap();
+
+        rotate_ip_hash_key(&config).unwrap();
+        let created: Vec<u64> = ip::ip_hash_keys()
+            .iter()
+            .skip(1)
+            .map(|k| k.created_at)
+            .collect();
+        assert_eq!(created, vec![due, 500, 100]);
+
+        unblock_ip(hashed).unwrap();
+        unblock_ip(tagged).unwrap();
+        ip::write_ip_hash_keys(&ip_hash_keys_path(&config), &original).unwrap();
+        *GLOBALS.ip_hash_keys.write() = original;
+    }
+
+    #[test]
+    fn test_compact_ip_data() {
+        let _guard = setup_test_store();
+
+        let stale = HashedIp::new("10.0.1.1".parse().unwrap());
+        let banned = HashedIp::new("10.0.1.2".parse().unwrap());
+        update_ip_data(stale, &IpData::default()).unwrap();
+        let ip_data = IpData {
+            ban_until: Time::now().as_u64() + 3600,
+            ..Default::default()
+        };
+        update_ip_data(banned, &ip_data).unwrap();
+
+        assert!(compact_ip_data(86400).unwrap() >= 1);
+        assert!(dump_ip_data()
+            .unwrap()
+            .iter()
+            .all(|(ip, data)| *ip != stale && !data.is_stale(Time::now().as_u64(), 86400)));
+        assert!(get_ip_data(banned).unwrap().is_banned());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_over_http2() {
+        use hyper_util::rt::TokioExecutor;
+        use tungstenite::protocol::Role;
+
+        // Nothing here changes the store, so other tests may use it meanwhile
+        drop(setup_test_store());
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let peer = HashedPeer::new("127.0.0.1:4000".parse().unwrap());
+        tokio::spawn(serve(TokioIo::new(server), peer, None, false));
+
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        // Opened with an extended CONNECT, answered with 200 and no accept key
+        let mut request = Request::builder()
+            .method(hyper::Method::CONNECT)
+            .uri("https://localhost/")
+            .header("sec-websocket-version", "13")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(hyper::ext::Protocol::from_static("websocket"));
+        let mut response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("sec-websocket-accept").is_none());
+
+        // The stream then carries websocket frames
+        let upgraded = hyper::upgrade::on(&mut response).await.unwrap();
+        let mut websocket =
+            WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Client, None).await;
+        websocket
+            .send(Message::Ping(b"hello"[..].into()))
+            .await
+            .unwrap();
+        loop {
+            match websocket.next().await.unwrap().unwrap() {
+                Message::Pong(payload) => {
+                    assert_eq!(&payload[..], b"hello");
+                    break;
+                }
+                // A relay may greet us first
+                _ => continue,
+            }
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use crate::config::{Config, ListenerConfig};
+use crate::counting_stream::CountingStream;
+use crate::error::Error;
+use crate::forwarded;
+use crate::globals::GLOBALS;
+use crate::ip::{HashedPeer, IpCidr};
+use crate::proxy_protocol;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+
+// The first file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listening socket with its settings
+#[derive(Debug)]
+pub struct Listener {
+    pub socket: Socket,
+    pub config: ListenerConfig,
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Tcp(l) => l.as_raw_fd(),
+            Socket::Unix(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+/// Take the listening sockets passed by systemd socket activation (LISTEN_FDS), if any
+pub fn systemd_sockets() -> Vec<OwnedFd> {
+    let pid_matches = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|p| p.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+
+    // These are meant for us only, not for any child process
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if !pid_matches {
+        return vec![];
+    }
+
+    // SAFETY: systemd passes us ownership of these descriptors
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Set up the configured listeners. Sockets that were passed to us (by systemd or by
+/// a handoff) are used for the listener with the same address, or with the default
+/// settings if none matches. Configured listeners without a passed socket are bound,
+/// except the implicit one on ip_address and port when sockets were passed.
+pub async fn setup_listeners(
+    config: &Config,
+    passed: Vec<OwnedFd>,
+) -> Result<Vec<Arc<Listener>>, Error> {
+    let mut listeners: Vec<Arc<Listener>> = Vec::new();
+    let mut unmatched = config.listeners.clone();
+    if !passed.is_empty() {
+        unmatched.retain(|l| !l.implicit);
+    }
+
+    for fd in passed {
+        let (socket, address) = socket_from_fd(fd)?;
+        let config = match unmatched.iter().position(|l| same_address(l, &address)) {
+            Some(i) => unmatched.remove(i),
+            None => ListenerConfig {
+                address,
+                tls: config.use_tls,
+                behind_proxy: config.chorus_is_behind_a_proxy,
+                proxy_protocol: false,
+                implicit: false,
+            },
+        };
+        log::info!(target: "Server", "Listening on {} (passed in)", config.address);
+        listeners.push(Arc::new(Listener { socket, config }));
+    }
+
+    for config in unmatched {
+        let socket = match config.unix_path() {
+            Some(path) => {
+                // Remove a socket left behind by a previous run
+                let _ = std::fs::remove_file(path);
+                Socket::Unix(UnixListener::bind(path)?)
+            }
+            None => Socket::Tcp(TcpListener::bind(&*config.address).await?),
+        };
+        log::info!(target: "Server", "Listening on {}", config.address);
+        listeners.push(Arc::new(Listener { socket, config }));
+    }
+
+    if config.trusted_proxies.is_empty()
+        && listeners
+            .iter()
+            .any(|l| l.config.behind_proxy && l.config.unix_path().is_none())
+    {
+        log::warn!(target: "Server", "Behind a proxy but trusted_proxies is not set: proxy headers are ignored");
+    }
+
+    Ok(listeners)
+}
+
+// Convert a passed-in listening socket, returning it with its address as we write it
+// in the config
+fn socket_from_fd(fd: OwnedFd) -> Result<(Socket, String), Error> {
+    // SAFETY: storage is large enough for any socket address
+    let family = unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(
+            fd.as_raw_fd(),
+            &mut storage as *mut _ as *mut libc::sockaddr,
+            &mut len,
+        ) < 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        storage.ss_family as libc::c_int
+    };
+
+    if family == libc::AF_UNIX {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        let path = listener
+            .local_addr()?
+            .as_pathname()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        Ok((
+            Socket::Unix(UnixListener::from_std(listener)?),
+            format!("unix:{path}"),
+        ))
+    } else {
+        let listener = std::net::TcpListener::from(fd);
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?.to_string();
+        Ok((Socket::Tcp(TcpListener::from_std(listener)?), address))
+    }
+}
+
+// Whether a configured listener is for this address
+fn same_address(config: &ListenerConfig, address: &str) -> bool {
+    if config.address == address {
+        return true;
+    }
+    match (
+        config.address.parse::<SocketAddr>(),
+        address.parse::<SocketAddr>(),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Accept connections on a listener and serve each one in its own task
+pub async fn accept_connections(listener: Arc<Listener>) {
+    loop {
+        let result = match &listener.socket {
+            Socket::Tcp(l) => l.accept().await.map(|(stream, peer_addr)| {
+                tokio::spawn(serve_connection(
+                    stream,
+                    Some(peer_addr),
+                    listener.config.clone(),
+                ));
+            }),
+            Socket::Unix(l) => l.accept().await.map(|(stream, _)| {
+                tokio::spawn(serve_connection(stream, None, listener.config.clone()));
+            }),
+        };
+
+        if let Err(e) = result {
+            log::error!(target: "Server", "{}: accept: {}", listener.config.address, e);
+            // Avoid spinning if we are out of file descriptors
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+// Serve a connection: read its PROXY protocol header if the listener expects one,
+// decide whether to admit the peer, then serve HTTP (with TLS if the listener uses it)
+async fn serve_connection<S>(stream: S, peer_addr: Option<SocketAddr>, config: ListenerConfig)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut counting_stream = CountingStream::new(stream);
+
+    // The address of the client, which a PROXY protocol header may give
+    let mut client_addr = peer_addr;
+    if config.proxy_protocol {
+        if !may_send_proxy_header(peer_addr, &GLOBALS.config.read().trusted_proxies) {
+            log::debug!(target: "Client", "{}: PROXY protocol from untrusted peer", config.address);
+            return;
+        }
+        let header = tokio::time::timeout(
+            Duration::from_secs(5),
+            proxy_protocol::read_header(&mut counting_stream),
+        )
+        .await;
+        match header {
+            Ok(Ok(Some(addr))) => client_addr = Some(addr),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                log::debug!(target: "Client", "{}: {}", config.address, e);
+                return;
+            }
+            Err(_) => {
+                log::debug!(target: "Client", "{}: PROXY protocol header timed out", config.address);
+                return;
+            }
+        }
+    }
+
+    // Proxy headers are trusted (or not) by the peer we actually talk to. We do not
+    // know the IP of peers on Unix domain sockets.
+    let source = peer_addr.map(|addr| addr.ip());
+    let peer_addr = client_addr.unwrap_or(SocketAddr::from(([127, 0, 0, 1], 0)));
+
+    let hashed_peer = match admit(peer_addr, &config) {
+        Some(hashed_peer) => hashed_peer,
+        None => return,
+    };
+
+    let maybe_tls_acceptor = if config.tls {
+        GLOBALS.tls_acceptor.read().clone()
+    } else {
+        None
+    };
+
+    match maybe_tls_acceptor {
+        Some(tls_acceptor) => match tls_acceptor.accept(counting_stream).await {
+            Ok(stream) => {
+                let io = TokioIo::new(stream);
+                crate::serve(io, hashed_peer, source, config.behind_proxy).await;
+            }
+            Err(e) => {
+                log::error!(
+                    target: "Client",
+                    "{}: TLS accept: {}", hashed_peer, e
+                );
+            }
+        },
+        None => {
+            let io = TokioIo::new(counting_stream);
+            crate::serve(io, hashed_peer, source, config.behind_proxy).await;
+        }
+    };
+}
+
+// Only trusted proxies may tell us the client address. Peers on Unix domain sockets
+// are local and trusted.
+fn may_send_proxy_header(peer_addr: Option<SocketAddr>, trusted: &[IpCidr]) -> bool {
+    match peer_addr {
+        Some(addr) => forwarded::is_trusted_proxy(addr.ip(), trusted),
+        None => true,
+    }
+}
+
+// Decide whether to serve a peer before reading any HTTP from it
+fn admit(peer_addr: SocketAddr, config: &ListenerConfig) -> Option<HashedPeer> {
+    let hashed_peer = HashedPeer::new(peer_addr);
+
+    // Behind a proxy, the peer is the proxy. The IP is checked once we have the
+    // real IP from the request headers.
+    if config.behind_proxy {
+        return Some(hashed_peer);
+    }
+
+    // Keep its reputation if the IP hash key has rotated since we last saw it
+    if let Err(e) = crate::carry_forward_ip_data(peer_addr.ip()) {
+        log::error!(target: "Client", "{}: {}", hashed_peer.ip(), e);
+    }
+
+    // Possibly IP block early
+    match crate::is_ip_blocked(peer_addr.ip()) {
+        Ok(false) => {}
+        Ok(true) => {
+            log::debug!(target: "Client", "{}: Blocked by moderator", hashed_peer.ip());
+            return None;
+        }
+        Err(e) => {
+            log::error!(target: "Client", "{}: {}", hashed_peer.ip(), e);
+            return None;
+        }
+    }
+    if GLOBALS.config.read().enable_ip_blocking {
+        match crate::peer_ban_until(hashed_peer) {
+            Ok(Some(ban_until)) => {
+                log::debug!(target: "Client",
+                            "{}: Blocking reconnection until {}",
+                            hashed_peer.ip(),
+                            ban_until);
+                // note: no need to shutdown() which only drops the write half.
+                // the whole thing gets dropped when we return.
+                return None;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log::error!(target: "Client", "{}: {}", hashed_peer.ip(), e);
+                return None;
+            }
+        }
+    }
+
+    Some(hashed_peer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn listener(address: &str) -> ListenerConfig {
+        ListenerConfig {
+            address: address.to_owned(),
+            tls: false,
+            behind_proxy: false,
+            proxy_protocol: false,
+            implicit: false,
+        }
+    }
+
+    #[test]
+    fn test_same_address() {
+        assert!(same_address(&listener("127.0.0.1:80"), "127.0.0.1:80"));
+        assert!(same_address(&listener("[::0]:443"), "[::]:443"));
+        assert!(same_address(
+            &listener("unix:/run/chorus.sock"),
+            "unix:/run/chorus.sock"
+        ));
+        assert!(!same_address(&listener("127.0.0.1:80"), "127.0.0.1:81"));
+        assert!(!same_address(&listener("0.0.0.0:80"), "[::]:80"));
+        assert!(!same_address(&listener("localhost:80"), "127.0.0.1:80"));
+    }
+
+    #[test]
+    fn test_may_send_proxy_header() {
+        let trusted: Vec<IpCidr> = vec!["10.0.0.0/8".parse().unwrap()];
+        let proxy: SocketAddr = "10.1.2.3:4000".parse().unwrap();
+        let mapped: SocketAddr = "[::ffff:10.1.2.3]:4000".parse().unwrap();
+        let client: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        assert!(may_send_proxy_header(Some(proxy), &trusted));
+        assert!(may_send_proxy_header(Some(mapped), &trusted));
+        assert!(!may_send_proxy_header(Some(client), &trusted));
+        assert!(!may_send_proxy_header(Some(proxy), &[]));
+        assert!(may_send_proxy_header(None, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_passed_sockets_replace_implicit_listener() {
+        let passed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = passed.local_addr().unwrap().to_string();
+
+        // The implicit listener is on a port we could not bind
+        let config = Config {
+            listeners: vec![ListenerConfig {
+                implicit: true,
+                ..listener("127.0.0.1:1")
+            }],
+            ..Default::default()
+        };
+
+        let listeners = setup_listeners(&config, vec![OwnedFd::from(passed)])
+            .await
+            .unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].config.address, address);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::Config;
+use crate::session::Session;
+use log::kv::{Error as KvError, Key, Value as KvValue, VisitSource};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+tokio::task_local! {
+    /// The websocket session whose task is running, so its log lines can be correlated
+    pub static SESSION: Arc<Session>;
+}
+
+/// Setup logging according to the config
+pub fn setup_logging(config: &Config) {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_level(config.library_log_level)
+        .filter(Some("Server"), config.server_log_level)
+        .filter(Some("Client"), config.client_log_level)
+        .format_target(true)
+        .format_module_path(false)
+        .format_timestamp_millis();
+
+    if config.log_format == "json" {
+        builder.format(|buf, record| {
+            let mut object = Map::new();
+            object.insert("time".to_owned(), buf.timestamp_millis().to_string().into());
+            object.insert("level".to_owned(), record.level().as_str().into());
+            object.insert("target".to_owned(), record.target().into());
+            let _ = SESSION.try_with(|session| {
+                object.insert("session".to_owned(), session.id.into());
+                object.insert("peer".to_owned(), session.peer.to_string().into());
+                if let Some(pubkey) = *session.user.read() {
+                    object.insert("pubkey".to_owned(), pubkey.as_hex_string().into());
+                }
+            });
+            let _ = record.key_values().visit(&mut JsonFields(&mut object));
+            object.insert("message".to_owned(), record.args().to_string().into());
+            writeln!(buf, "{}", Value::Object(object))
+        });
+    }
+
+    if let Some(path) = &config.log_file {
+        match RotatingFile::open(
+            PathBuf::from(path),
+            config.log_file_max_bytes,
+            config.log_file_keep,
+        ) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            Err(e) => eprintln!("Could not open log file {path}, logging to stderr: {e}"),
+        }
+    }
+
+    builder.init();
+
+    log::debug!(target: "Server", "Loaded config file.");
+}
+
+// Copies key-value pairs from a log record into a JSON object
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: KvValue<'kvs>) -> Result<(), KvError> {
+        let value: Value = if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(u) = value.to_u64() {
+            u.into()
+        } else if let Some(i) = value.to_i64() {
+            i.into()
+        } else if let Some(f) = value.to_f64() {
+            f.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_owned(), value);
+        Ok(())
+    }
+}
+
+/// A log file that is rotated when it grows beyond `max_bytes`. The previous files are
+/// renamed with suffixes `.1` (newest) to `.<keep>` (oldest).
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut s = self.path.clone().into_os_string();
+        s.push(format!(".{n}"));
+        PathBuf::from(s)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_bytes > 0
+            && self.written > 0
+            && self.written + buf.len() as u64 > self.max_bytes
+        {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -2,12 +2,18 @@ use crate::error::{ChorusError, Error};
 use crate::globals::GLOBALS;
 use crate::neg_storage::NegentropyStorageVector;
 use crate::reply::{NostrReply, NostrReplyPrefix};
+use crate::roles::Permission;
+use crate::stats::MessageType;
+use crate::web::management;
 use crate::WebSocketService;
 use hyper_tungstenite::tungstenite::Message;
 use negentropy::Negentropy;
 use pocket_db::ScreenResult;
 use pocket_types::json::{eat_whitespace, json_unescape, verify_char};
 use pocket_types::{read_hex, Event, Filter, Hll8, Kind, OwnedFilter, Pubkey, Time};
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+use tokio::time::Instant;
 use url::Url;
 
 impl WebSocketService {
@@ -27,29 +33,52 @@ impl WebSocketService {
         verify_char(input, b'[', &mut inpos)?;
         eat_whitespace(input, &mut inpos);
         verify_char(input, b'"', &mut inpos)?;
-        if &input[inpos..inpos + 4] == b"REQ\"" {
-            self.req(msg, inpos + 4, false).await?;
+        let started = Instant::now();
+        self.subid = None;
+        let (message_type, result) = if &input[inpos..inpos + 4] == b"REQ\"" {
+            (MessageType::Req, self.req(msg, inpos + 4, false).await)
         } else if &input[inpos..inpos + 6] == b"COUNT\"" {
-            self.req(msg, inpos + 6, true).await?;
+            (MessageType::Count, self.req(msg, inpos + 6, true).await)
         } else if &input[inpos..inpos + 6] == b"EVENT\"" {
-            self.event(msg, inpos + 6).await?;
+            (MessageType::Event, self.event(msg, inpos + 6).await)
         } else if &input[inpos..inpos + 6] == b"CLOSE\"" {
-            self.close(msg, inpos + 6).await?;
+            (MessageType::Close, self.close(msg, inpos + 6).await)
         } else if &input[inpos..inpos + 5] == b"AUTH\"" {
-            self.auth(msg, inpos + 5).await?;
+            (MessageType::Auth, self.auth(msg, inpos + 5).await)
         } else if &input[inpos..inpos + 9] == b"NEG-OPEN\"" {
-            self.neg_open(msg, inpos + 9).await?;
+            (MessageType::NegOpen, self.neg_open(msg, inpos + 9).await)
         } else if &input[inpos..inpos + 8] == b"NEG-MSG\"" {
-            self.neg_msg(msg, inpos + 8).await?;
+            (MessageType::NegMsg, self.neg_msg(msg, inpos + 8).await)
         } else if &input[inpos..inpos + 10] == b"NEG-CLOSE\"" {
-            self.neg_close(msg, inpos + 10).await?;
+            (MessageType::NegClose, self.neg_close(msg, inpos + 10).await)
+        } else if input[inpos..].starts_with(b"MANAGE\"") {
+            (MessageType::Manage, self.manage(msg).await)
         } else {
+            GLOBALS.stats.count_message(MessageType::Unknown);
             log::warn!(target: "Client", "{}: Received unhandled text message: {}", self.peer, msg);
             let reply = NostrReply::Notice("Command unrecognized".to_owned());
             self.send(Message::text(reply.as_json()?)).await?;
-        }
+            return Ok(());
+        };
 
-        Ok(())
+        GLOBALS.stats.count_message(message_type);
+
+        // One structured line per command, for log pipelines
+        let latency_us = started.elapsed().as_micros() as u64;
+        let outcome = match &result {
+            Ok(()) => "ok".to_owned(),
+            Err(e) => format!("{}", e.inner),
+        };
+        log::debug!(
+            target: "Client",
+            command = message_type.name(),
+            subid = self.subid.as_deref().unwrap_or(""),
+            outcome = outcome.as_str(),
+            latency_us = latency_us;
+            "{}: {} {} in {}us", self.peer, message_type.name(), outcome, latency_us
+        );
+
+        result
     }
 
     pub async fn req(&mut self, msg: &str, mut inpos: usize, count: bool) -> Result<(), Error> {
@@ -71,6 +100,7 @@ impl WebSocketService {
             unsafe { String::from_utf8_unchecked(self.buffer[outpos..outpos + outlen].to_owned()) };
         outpos += outlen;
         verify_char(input, b'"', &mut inpos)?; // FIXME: json_unescape should eat the closing quote
+        self.subid = Some(subid.clone());
 
         // Read the filter into the session buffer
         let mut filters: Vec<OwnedFilter> = Vec::new();
@@ -89,7 +119,13 @@ impl WebSocketService {
             filters.push(filter.to_owned());
         }
 
-        if let Err(e) = self.req_inner(&subid, filters, count).await {
+        let started = Instant::now();
+        let result = self.req_inner(&subid, filters, count).await;
+        if !count {
+            GLOBALS.stats.observe_req(started.elapsed());
+        }
+
+        if let Err(e) = result {
             let reply = match e.inner {
                 ChorusError::TooManySubscriptions => {
                     let max_subscriptions = GLOBALS.config.read().max_subscriptions;
@@ -125,7 +161,7 @@ impl WebSocketService {
         }
 
         let user = self.user;
-        let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
+        let authorized_user = self.permissions().contains(Permission::ReadPrivate);
 
         if user.is_none() {
             for filter in filters.iter() {
@@ -259,6 +295,7 @@ impl WebSocketService {
         // Read the event into the session buffer
         let (_incount, event) = Event::from_json(&input[inpos..], &mut self.buffer)?;
         let id = event.id();
+        let kind = event.kind();
 
         if let Err(e) = self.event_inner().await {
             let reply = match e.inner {
@@ -307,9 +344,15 @@ impl WebSocketService {
                 },
                 _ => NostrReply::Ok(id, false, NostrReplyPrefix::Error, format!("{}", e.inner)),
             };
+            if let NostrReply::Ok(_, _, prefix, _) = reply {
+                GLOBALS.stats.count_event(prefix, kind, Some(&e.inner));
+            }
             self.send(Message::text(reply.as_json()?)).await?;
             Err(e)
         } else {
+            GLOBALS
+                .stats
+                .count_event(NostrReplyPrefix::None, kind, None);
             let reply = NostrReply::Ok(id, true, NostrReplyPrefix::None, "".to_string());
             self.send(Message::text(reply.as_json()?)).await?;
             Ok(())
@@ -318,7 +361,7 @@ impl WebSocketService {
 
     async fn event_inner(&mut self) -> Result<(), Error> {
         let user = self.user;
-        let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
+        let authorized_user = self.permissions().contains(Permission::Write);
 
         // Delineate the event back out of the session buffer
         let event = unsafe { Event::delineate(&self.buffer)? };
@@ -358,6 +401,56 @@ impl WebSocketService {
         let offset = GLOBALS.store.get().unwrap().store_event(event)?;
         GLOBALS.new_events.send(offset)?; // advertise the new event
 
+        // Let moderators on the websocket management channel know
+        if GLOBALS.management_notifications.receiver_count() > 0 {
+            if event.kind() == Kind::from(1984) {
+                management::notify_event("report", event);
+            } else if management::needs_moderation(event) {
+                management::notify_event("moderation", event);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn manage(&mut self, msg: &str) -> Result<(), Error> {
+        // ["MANAGE", <id>, {"method": <method>, "params": [...]}]
+
+        let value: Value = serde_json::from_str(msg)?;
+        let (id, command) = match value.as_array().map(|a| a.as_slice()) {
+            Some([_, Value::String(id), command]) => (id.to_owned(), command.to_owned()),
+            _ => return Err(ChorusError::BadRequest("MANAGE message is malformed").into()),
+        };
+
+        let permissions = self.permissions();
+        let response = match self.user {
+            Some(pubkey) if permissions.can_manage() => {
+                match command.get("method").and_then(|m| m.as_str()) {
+                    // These only make sense over websocket
+                    Some("subscribe") => {
+                        self.management_notifications =
+                            Some(GLOBALS.management_notifications.subscribe());
+                        json!({ "result": true })
+                    }
+                    Some("unsubscribe") => {
+                        self.management_notifications = None;
+                        json!({ "result": true })
+                    }
+                    _ => management::run_command(pubkey, permissions, command).0,
+                }
+            }
+            Some(_) => json!({
+                "result": {},
+                "error": "restricted: you are not a moderator"
+            }),
+            None => json!({
+                "result": {},
+                "error": "auth-required: you must AUTH as a moderator"
+            }),
+        };
+
+        let reply = NostrReply::Manage(&id, response);
+        self.send(Message::text(reply.as_json()?)).await?;
         Ok(())
     }
 
@@ -376,6 +469,7 @@ impl WebSocketService {
 
         // consider as a &str
         let subid = unsafe { std::str::from_utf8_unchecked(&self.buffer[..outlen]) };
+        self.subid = Some(subid.to_owned());
 
         // If we have that subscription
         if self.subscriptions.contains_key(subid) {
@@ -464,6 +558,7 @@ impl WebSocketService {
 
         // They are now authenticated
         self.user = Some(event.pubkey());
+        self.permissions = None;
 
         Ok(())
     }
@@ -493,6 +588,7 @@ impl WebSocketService {
         };
 
         self.negentropy_sub = Some(subid.clone());
+        self.subid = Some(subid.clone());
 
         if !GLOBALS.config.read().enable_negentropy {
             let reply =
@@ -543,7 +639,7 @@ impl WebSocketService {
         }
 
         let user = self.user;
-        let authorized_user = self.user.map(crate::is_authorized_user).unwrap_or(false);
+        let authorized_user = self.permissions().contains(Permission::ReadPrivate);
 
         // Find all matching events
         let mut events: Vec<&Event> = Vec::new();
@@ -579,6 +675,10 @@ impl WebSocketService {
 
         // Save the matching events under the subscription Id
         self.neg_subscriptions.insert(subid.clone(), nsv);
+        GLOBALS
+            .stats
+            .negentropy_sessions
+            .fetch_add(1, Ordering::Relaxed);
 
         // Look it up again immediately
         let Some(nsv) = self.neg_subscriptions.get(&subid) else {
@@ -628,6 +728,7 @@ impl WebSocketService {
         };
 
         self.negentropy_sub = Some(subid.clone());
+        self.subid = Some(subid.clone());
 
         if !GLOBALS.config.read().enable_negentropy {
             let reply =
@@ -708,6 +809,7 @@ impl WebSocketService {
         };
 
         self.negentropy_sub = Some(subid.clone());
+        self.subid = Some(subid.clone());
 
         // Close the subscription
         self.neg_subscriptions.remove(&subid);
@@ -767,7 +869,7 @@ async fn screen_incoming_event(
     }
 
     // If the author is one of our users, always accept it
-    if crate::is_authorized_user(event.pubkey()) {
+    if crate::has_permission(event.pubkey(), Permission::Write) {
         return Ok(true);
     }
 
@@ -0,0 +1,179 @@
+use crate::error::{ChorusError, Error};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The signature that starts a version 2 header
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+// The longest version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read a PROXY protocol header (version 1 or 2) from the start of a stream, leaving
+/// the stream at the first byte after it. Returns the client address it gives, or None
+/// if the proxy did not give one (e.g. for its own health checks), in which case the
+/// address of the connection itself applies.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions can be told apart by their first 5 bytes. We never read past the
+    // header, as what follows belongs to TLS or HTTP.
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        let mut line: Vec<u8> = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if start == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 16];
+        header[..5].copy_from_slice(&start);
+        stream.read_exact(&mut header[5..]).await?;
+        if header[..12] != V2_SIGNATURE {
+            return Err(invalid("bad signature"));
+        }
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(&header, &body)
+    } else {
+        Err(invalid("missing"))
+    }
+}
+
+fn invalid(reason: &'static str) -> Error {
+    ChorusError::ProxyProtocol(reason).into()
+}
+
+// Parse a version 1 (text) header, including its CRLF
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("not ASCII"))?
+        .strip_suffix("\r\n")
+        .ok_or_else(|| invalid("missing CRLF"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "PROXY" {
+        return Err(invalid("malformed"));
+    }
+    if parts[1] == "UNKNOWN" {
+        return Ok(None);
+    }
+    if parts.len() != 6 {
+        return Err(invalid("malformed"));
+    }
+    let ip: IpAddr = match parts[1] {
+        "TCP4" => parts[2]
+            .parse::<Ipv4Addr>()
+            .map_err(|_| invalid("bad address"))?
+            .into(),
+        "TCP6" => parts[2]
+            .parse::<Ipv6Addr>()
+            .map_err(|_| invalid("bad address"))?
+            .into(),
+        _ => return Err(invalid("unknown protocol")),
+    };
+    let port: u16 = parts[4].parse().map_err(|_| invalid("bad port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+// Parse a version 2 (binary) header: the fixed 16 bytes and the address block
+fn parse_v2(header: &[u8; 16], body: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    if header[12] >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match header[12] & 0x0F {
+        0 => return Ok(None), // LOCAL: the proxy's own connection
+        1 => {}               // PROXY
+        _ => return Err(invalid("unknown command")),
+    }
+
+    match header[13] >> 4 {
+        // AF_INET
+        1 => {
+            if body.len() < 12 {
+                return Err(invalid("truncated"));
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 => {
+            if body.len() < 36 {
+                return Err(invalid("truncated"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX: no IP address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy_protocol_v1() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET /";
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(input, b"GET /");
+
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n";
+        let addr = read_header(&mut input).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234\r\n";
+        assert!(read_header(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_v2() {
+        let mut input: Vec<u8> = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0, 12]);
+        input.extend([203, 0, 113, 7, 192, 0, 2, 1, 0xC8, 0x22, 0x01, 0xBB]);
+        input.extend(b"GET /");
+        let mut reader: &[u8] = &input;
+        let addr = read_header(&mut reader).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(reader, b"GET /");
+
+        let mut input: Vec<u8> = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x21, 0, 36]);
+        input.extend("2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+        input.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        input.extend([0xC8, 0x22, 0x01, 0xBB]);
+        let mut reader: &[u8] = &input;
+        let addr = read_header(&mut reader).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        // LOCAL, as sent by health checks
+        let mut input: Vec<u8> = V2_SIGNATURE.to_vec();
+        input.extend([0x20, 0x00, 0, 0]);
+        let mut reader: &[u8] = &input;
+        assert_eq!(read_header(&mut reader).await.unwrap(), None);
+
+        // Truncated address block
+        let mut input: Vec<u8> = V2_SIGNATURE.to_vec();
+        input.extend([0x21, 0x11, 0, 4, 203, 0, 113, 7]);
+        let mut reader: &[u8] = &input;
+        assert!(read_header(&mut reader).await.is_err());
+    }
+}
@@ -15,6 +15,40 @@ pub enum NostrReplyPrefix {
     Error,
 }
 
+impl NostrReplyPrefix {
+    pub const ALL: [NostrReplyPrefix; 9] = [
+        NostrReplyPrefix::None,
+        NostrReplyPrefix::AuthRequired,
+        NostrReplyPrefix::Pow,
+        NostrReplyPrefix::Duplicate,
+        NostrReplyPrefix::Blocked,
+        NostrReplyPrefix::RateLimited,
+        NostrReplyPrefix::Restricted,
+        NostrReplyPrefix::Invalid,
+        NostrReplyPrefix::Error,
+    ];
+
+    /// The position of this prefix in `ALL`
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The machine-readable prefix, without the colon
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            NostrReplyPrefix::None => None,
+            NostrReplyPrefix::AuthRequired => Some("auth-required"),
+            NostrReplyPrefix::Pow => Some("pow"),
+            NostrReplyPrefix::Duplicate => Some("duplicate"),
+            NostrReplyPrefix::Blocked => Some("blocked"),
+            NostrReplyPrefix::RateLimited => Some("rate-limited"),
+            NostrReplyPrefix::Restricted => Some("restricted"),
+            NostrReplyPrefix::Invalid => Some("invalid"),
+            NostrReplyPrefix::Error => Some("error"),
+        }
+    }
+}
+
 impl fmt::Display for NostrReplyPrefix {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -43,6 +77,7 @@ pub enum NostrReply<'a> {
     Count(&'a str, usize, Option<Hll8>),
     NegErr(&'a str, String),
     NegMsg(&'a str, Vec<u8>),
+    Manage(&'a str, serde_json::Value),
 }
 
 impl NostrReply<'_> {
@@ -93,6 +128,10 @@ impl NostrReply<'_> {
                 let msg_hex = unsafe { std::str::from_utf8_unchecked(&buf) };
                 format!(r#"["NEG-MSG","{esc_subid}","{}"]"#, msg_hex)
             }
+            NostrReply::Manage(id, response) => {
+                let esc_id = escape(id)?;
+                format!(r#"["MANAGE","{esc_id}",{response}]"#)
+            }
         })
     }
 }
@@ -0,0 +1,187 @@
+use crate::error::{ChorusError, Error};
+use std::fmt;
+use std::str::FromStr;
+
+/// Something a pubkey may be permitted to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// Post events which are accepted without moderation
+    Write,
+
+    /// Read events that are not publicly visible
+    ReadPrivate,
+
+    /// Use the blossom server
+    BlossomUpload,
+
+    /// Ban and allow events, pubkeys and IP addresses
+    CanBan,
+
+    /// Remove events and blobs
+    CanDelete,
+
+    /// Grant and revoke roles
+    CanManageUsers,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Permission::Write,
+        Permission::ReadPrivate,
+        Permission::BlossomUpload,
+        Permission::CanBan,
+        Permission::CanDelete,
+        Permission::CanManageUsers,
+    ];
+
+    fn bit(self) -> u8 {
+        match self {
+            Permission::Write => 1 << 0,
+            Permission::ReadPrivate => 1 << 1,
+            Permission::BlossomUpload => 1 << 2,
+            Permission::CanBan => 1 << 3,
+            Permission::CanDelete => 1 << 4,
+            Permission::CanManageUsers => 1 << 5,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Permission::Write => "write",
+            Permission::ReadPrivate => "read-private",
+            Permission::BlossomUpload => "blossom-upload",
+            Permission::CanBan => "can-ban",
+            Permission::CanDelete => "can-delete",
+            Permission::CanManageUsers => "can-manage-users",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Permission, Error> {
+        Permission::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or(ChorusError::BadRequest("Unknown permission").into())
+    }
+}
+
+/// A set of permissions, stored as a bitmask
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PermissionSet(u8);
+
+impl PermissionSet {
+    pub fn from_bits(bits: u8) -> PermissionSet {
+        PermissionSet(bits)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn with(self, permission: Permission) -> PermissionSet {
+        PermissionSet(self.0 | permission.bit())
+    }
+
+    pub fn union(self, other: PermissionSet) -> PermissionSet {
+        PermissionSet(self.0 | other.0)
+    }
+
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.0 & permission.bit() != 0
+    }
+
+    pub fn contains_all(&self, other: PermissionSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Does the set allow using the management API at all?
+    pub fn can_manage(&self) -> bool {
+        self.contains(Permission::CanBan)
+            || self.contains(Permission::CanDelete)
+            || self.contains(Permission::CanManageUsers)
+    }
+
+    pub fn permissions(&self) -> Vec<Permission> {
+        Permission::ALL
+            .into_iter()
+            .filter(|p| self.contains(*p))
+            .collect()
+    }
+}
+
+impl FromIterator<Permission> for PermissionSet {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> PermissionSet {
+        iter.into_iter()
+            .fold(PermissionSet::default(), |set, p| set.with(p))
+    }
+}
+
+/// Roles that always exist and cannot be redefined
+pub const BUILTIN_ROLES: [&str; 3] = ["user", "moderator", "admin"];
+
+/// The permissions of a built-in role
+pub fn builtin_role(name: &str) -> Option<PermissionSet> {
+    let user: PermissionSet = [
+        Permission::Write,
+        Permission::ReadPrivate,
+        Permission::BlossomUpload,
+    ]
+    .into_iter()
+    .collect();
+    let moderator = user.with(Permission::CanBan).with(Permission::CanDelete);
+    let admin = moderator.with(Permission::CanManageUsers);
+
+    match name {
+        "user" => Some(user),
+        "moderator" => Some(moderator),
+        "admin" => Some(admin),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permission_set() {
+        let set: PermissionSet = [Permission::Write, Permission::CanBan]
+            .into_iter()
+            .collect();
+        assert!(set.contains(Permission::Write));
+        assert!(!set.contains(Permission::CanDelete));
+        assert_eq!(
+            set.permissions(),
+            vec![Permission::Write, Permission::CanBan]
+        );
+        assert_eq!(PermissionSet::from_bits(set.bits()), set);
+        assert!(PermissionSet::default().is_empty());
+
+        let user = builtin_role("user").unwrap();
+        let moderator = builtin_role("moderator").unwrap();
+        let admin = builtin_role("admin").unwrap();
+        assert!(admin.contains_all(moderator));
+        assert!(moderator.contains_all(user));
+        assert!(!user.contains_all(moderator));
+        assert!(!set.contains_all(user));
+        assert!(set.union(user).contains_all(user));
+        assert!(set.contains_all(PermissionSet::default()));
+
+        assert_eq!("can-ban".parse::<Permission>().unwrap(), Permission::CanBan);
+        assert!("can-fly".parse::<Permission>().is_err());
+        assert_eq!(builtin_role("nobody"), None);
+    }
+}
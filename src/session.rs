@@ -0,0 +1,128 @@
+use crate::globals::GLOBALS;
+use crate::ip::HashedPeer;
+use parking_lot::RwLock;
+use pocket_types::{Pubkey, Time};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// A live websocket session, shared between its WebSocketService and the registry
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub peer: HashedPeer,
+    pub connected_since: Time,
+    pub origin: String,
+    pub user_agent: String,
+    pub user: RwLock<Option<Pubkey>>,
+    pub subscriptions: AtomicUsize,
+    pub neg_subscriptions: AtomicUsize,
+
+    /// Websocket message bytes received from and sent to the client
+    pub bytes_inbound: AtomicU64,
+    pub bytes_outbound: AtomicU64,
+
+    kick: Notify,
+}
+
+/// A snapshot of a session, suitable for reporting
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionReport {
+    pub id: u64,
+    pub peer: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<String>,
+    pub subscriptions: usize,
+    pub negentropy_subscriptions: usize,
+    pub connected_since: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub user_agent: String,
+    pub origin: String,
+}
+
+impl Session {
+    pub fn report(&self) -> SessionReport {
+        SessionReport {
+            id: self.id,
+            peer: format!("{}", self.peer),
+            pubkey: self.user.read().map(|pk| pk.as_hex_string()),
+            subscriptions: self.subscriptions.load(Ordering::Relaxed),
+            negentropy_subscriptions: self.neg_subscriptions.load(Ordering::Relaxed),
+            connected_since: self.connected_since.as_u64(),
+            bytes_received: self.bytes_inbound.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_outbound.load(Ordering::Relaxed),
+            user_agent: self.user_agent.clone(),
+            origin: self.origin.clone(),
+        }
+    }
+
+    /// Ask the session to close. This is remembered if it is not currently waiting.
+    pub fn kick(&self) {
+        self.kick.notify_one();
+    }
+
+    /// Wait until the session is kicked
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+}
+
+/// Register a new websocket session
+pub fn register_session(peer: HashedPeer, origin: String, user_agent: String) -> Arc<Session> {
+    let session = Arc::new(Session {
+        id: GLOBALS.next_session_id.fetch_add(1, Ordering::Relaxed),
+        peer,
+        connected_since: Time::now(),
+        origin,
+        user_agent,
+        user: RwLock::new(None),
+        subscriptions: AtomicUsize::new(0),
+        neg_subscriptions: AtomicUsize::new(0),
+        bytes_inbound: AtomicU64::new(0),
+        bytes_outbound: AtomicU64::new(0),
+        kick: Notify::new(),
+    });
+    GLOBALS.sessions.insert(session.id, session.clone());
+    session
+}
+
+/// Forget a websocket session once it has closed
+pub fn unregister_session(id: u64) {
+    GLOBALS.sessions.remove(&id);
+}
+
+/// Report on all live sessions, oldest first
+pub fn list_sessions() -> Vec<SessionReport> {
+    let mut reports: Vec<SessionReport> = GLOBALS
+        .sessions
+        .iter()
+        .map(|s| s.value().report())
+        .collect();
+    reports.sort_by_key(|r| r.id);
+    reports
+}
+
+/// Kick a session by id. Returns false if there is no such session.
+pub fn kick_session(id: u64) -> bool {
+    match GLOBALS.sessions.get(&id) {
+        Some(session) => {
+            session.kick();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Kick every session authenticated as the pubkey. Returns how many were kicked.
+pub fn kick_pubkey(pubkey: Pubkey) -> usize {
+    let mut count: usize = 0;
+    for session in GLOBALS.sessions.iter() {
+        if *session.user.read() == Some(pubkey) {
+            session.kick();
+            count += 1;
+        }
+    }
+    count
+}
@@ -0,0 +1,468 @@
+use crate::error::ChorusError;
+use crate::globals::GLOBALS;
+use crate::reply::NostrReplyPrefix;
+use dashmap::DashMap;
+use pocket_types::Kind;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The kinds of client message we count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Req,
+    Count,
+    Event,
+    Close,
+    Auth,
+    NegOpen,
+    NegMsg,
+    NegClose,
+    Manage,
+    Unknown,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; 10] = [
+        MessageType::Req,
+        MessageType::Count,
+        MessageType::Event,
+        MessageType::Close,
+        MessageType::Auth,
+        MessageType::NegOpen,
+        MessageType::NegMsg,
+        MessageType::NegClose,
+        MessageType::Manage,
+        MessageType::Unknown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageType::Req => "REQ",
+            MessageType::Count => "COUNT",
+            MessageType::Event => "EVENT",
+            MessageType::Close => "CLOSE",
+            MessageType::Auth => "AUTH",
+            MessageType::NegOpen => "NEG-OPEN",
+            MessageType::NegMsg => "NEG-MSG",
+            MessageType::NegClose => "NEG-CLOSE",
+            MessageType::Manage => "MANAGE",
+            MessageType::Unknown => "unknown",
+        }
+    }
+}
+
+/// What moderators have banned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanType {
+    Event,
+    Pubkey,
+    Ip,
+}
+
+impl BanType {
+    pub const ALL: [BanType; 3] = [BanType::Event, BanType::Pubkey, BanType::Ip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BanType::Event => "event",
+            BanType::Pubkey => "pubkey",
+            BanType::Ip => "ip",
+        }
+    }
+}
+
+/// The kind an event is counted under: one of the configured metrics_event_kinds,
+/// or other, so that arbitrary kinds cannot create unbounded label values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KindLabel {
+    Kind(u16),
+    Other,
+}
+
+impl KindLabel {
+    pub fn new(kind: Kind) -> KindLabel {
+        let kind = kind.as_u16();
+        if GLOBALS.config.read().metrics_event_kinds.contains(&kind) {
+            KindLabel::Kind(kind)
+        } else {
+            KindLabel::Other
+        }
+    }
+}
+
+impl fmt::Display for KindLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KindLabel::Kind(kind) => write!(f, "{kind}"),
+            KindLabel::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl Serialize for KindLabel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Upper bounds (in seconds) of the REQ latency histogram buckets
+const REQ_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+/// Counters exported as metrics. Gauges are read from elsewhere when rendering.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub connections: AtomicU64,
+    messages: [AtomicU64; MessageType::ALL.len()],
+    events: [AtomicU64; NostrReplyPrefix::ALL.len()],
+    event_errors: DashMap<&'static str, AtomicU64>,
+    event_kinds: DashMap<KindLabel, [AtomicU64; NostrReplyPrefix::ALL.len()]>,
+    bans: [AtomicU64; BanType::ALL.len()],
+    pub negentropy_sessions: AtomicU64,
+    req_buckets: [AtomicU64; REQ_BUCKETS.len()],
+    req_count: AtomicU64,
+    req_micros: AtomicU64,
+}
+
+impl Stats {
+    pub fn count_message(&self, message_type: MessageType) {
+        let index = MessageType::ALL
+            .iter()
+            .position(|t| *t == message_type)
+            .unwrap();
+        self.messages[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the result of an EVENT by the prefix of our OK reply, by the error
+    /// that caused it (if any), and by the event kind (see KindLabel)
+    pub fn count_event(&self, prefix: NostrReplyPrefix, kind: Kind, error: Option<&ChorusError>) {
+        self.events[prefix.index()].fetch_add(1, Ordering::Relaxed);
+        if let Some(error) = error {
+            self.event_errors
+                .entry(error.name())
+                .or_default()
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.event_kinds.entry(KindLabel::new(kind)).or_default()[prefix.index()]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A snapshot of the EVENT counters
+    pub fn event_report(&self) -> EventReport {
+        let results = |counts: &[AtomicU64; NostrReplyPrefix::ALL.len()]| {
+            NostrReplyPrefix::ALL
+                .iter()
+                .zip(counts.iter())
+                .map(|(prefix, count)| (result_name(*prefix), count.load(Ordering::Relaxed)))
+                .filter(|(_, count)| *count > 0)
+                .collect::<BTreeMap<&'static str, u64>>()
+        };
+        EventReport {
+            by_result: results(&self.events),
+            by_error: self
+                .event_errors
+                .iter()
+                .map(|e| (*e.key(), e.value().load(Ordering::Relaxed)))
+                .collect(),
+            by_kind: self
+                .event_kinds
+                .iter()
+                .map(|k| (*k.key(), results(k.value())))
+                .collect(),
+        }
+    }
+
+    pub fn count_ban(&self, ban_type: BanType) {
+        let index = BanType::ALL.iter().position(|t| *t == ban_type).unwrap();
+        self.bans[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_req(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.req_buckets.iter().zip(REQ_BUCKETS.iter()) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.req_count.fetch_add(1, Ordering::Relaxed);
+        self.req_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// EVENT counters: by the prefix of our OK reply ("accepted" if none), by the error
+/// that caused a rejection, and by kind and then prefix
+#[derive(Debug, Clone, Serialize)]
+pub struct EventReport {
+    pub by_result: BTreeMap<&'static str, u64>,
+    pub by_error: BTreeMap<&'static str, u64>,
+    pub by_kind: BTreeMap<KindLabel, BTreeMap<&'static str, u64>>,
+}
+
+fn result_name(prefix: NostrReplyPrefix) -> &'static str {
+    prefix.name().unwrap_or("accepted")
+}
+
+// Write the HELP and TYPE lines of a metric
+fn header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {metric_type}");
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render_prometheus() -> String {
+    let stats = &GLOBALS.stats;
+    let mut output = String::new();
+
+    header(
+        &mut output,
+        "chorus_uptime_seconds",
+        "gauge",
+        "Seconds since chorus started",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_uptime_seconds {}",
+        GLOBALS.start_time.elapsed().as_secs()
+    );
+
+    header(
+        &mut output,
+        "chorus_connections",
+        "gauge",
+        "Open websocket connections",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_connections {}",
+        GLOBALS.num_connections.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_connections_total",
+        "counter",
+        "Websocket connections accepted",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_connections_total {}",
+        stats.connections.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_bytes_received_total",
+        "counter",
+        "Bytes received from clients",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_bytes_received_total {}",
+        GLOBALS.bytes_inbound.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_bytes_sent_total",
+        "counter",
+        "Bytes sent to clients",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_bytes_sent_total {}",
+        GLOBALS.bytes_outbound.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_websocket_bytes_received_uncompressed_total",
+        "counter",
+        "Websocket bytes received from clients, after decompression",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_websocket_bytes_received_uncompressed_total {}",
+        GLOBALS.bytes_inbound_uncompressed.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_websocket_bytes_sent_uncompressed_total",
+        "counter",
+        "Websocket bytes sent to clients, before compression",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_websocket_bytes_sent_uncompressed_total {}",
+        GLOBALS.bytes_outbound_uncompressed.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut output,
+        "chorus_messages_total",
+        "counter",
+        "Websocket messages received, by type",
+    );
+    for (message_type, count) in MessageType::ALL.iter().zip(stats.messages.iter()) {
+        let _ = writeln!(
+            output,
+            "chorus_messages_total{{type=\"{}\"}} {}",
+            message_type.name(),
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut output,
+        "chorus_events_total",
+        "counter",
+        "Events submitted, by the prefix of the OK reply (accepted if none)",
+    );
+    for (prefix, count) in NostrReplyPrefix::ALL.iter().zip(stats.events.iter()) {
+        let _ = writeln!(
+            output,
+            "chorus_events_total{{result=\"{}\"}} {}",
+            result_name(*prefix),
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    let event_report = stats.event_report();
+
+    header(
+        &mut output,
+        "chorus_event_errors_total",
+        "counter",
+        "Events not accepted, by the error that caused it",
+    );
+    for (error, count) in event_report.by_error.iter() {
+        let _ = writeln!(
+            output,
+            "chorus_event_errors_total{{error=\"{error}\"}} {count}"
+        );
+    }
+
+    header(
+        &mut output,
+        "chorus_events_by_kind_total",
+        "counter",
+        "Events submitted, by kind and by the prefix of the OK reply",
+    );
+    for (kind, results) in event_report.by_kind.iter() {
+        for (result, count) in results.iter() {
+            let _ = writeln!(
+                output,
+                "chorus_events_by_kind_total{{kind=\"{kind}\",result=\"{result}\"}} {count}"
+            );
+        }
+    }
+
+    header(
+        &mut output,
+        "chorus_req_duration_seconds",
+        "histogram",
+        "Time to answer REQ messages up to EOSE",
+    );
+    for (bound, count) in REQ_BUCKETS.iter().zip(stats.req_buckets.iter()) {
+        let _ = writeln!(
+            output,
+            "chorus_req_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            count.load(Ordering::Relaxed)
+        );
+    }
+    let req_count = stats.req_count.load(Ordering::Relaxed);
+    let _ = writeln!(
+        output,
+        "chorus_req_duration_seconds_bucket{{le=\"+Inf\"}} {req_count}"
+    );
+    let _ = writeln!(
+        output,
+        "chorus_req_duration_seconds_sum {}",
+        stats.req_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(output, "chorus_req_duration_seconds_count {req_count}");
+
+    header(
+        &mut output,
+        "chorus_bans_total",
+        "counter",
+        "Bans issued by moderators, by what was banned",
+    );
+    for (ban_type, count) in BanType::ALL.iter().zip(stats.bans.iter()) {
+        let _ = writeln!(
+            output,
+            "chorus_bans_total{{type=\"{}\"}} {}",
+            ban_type.name(),
+            count.load(Ordering::Relaxed)
+        );
+    }
+
+    header(
+        &mut output,
+        "chorus_negentropy_sessions",
+        "gauge",
+        "Open negentropy sync sessions",
+    );
+    let open: usize = GLOBALS
+        .sessions
+        .iter()
+        .map(|s| s.neg_subscriptions.load(Ordering::Relaxed))
+        .sum();
+    let _ = writeln!(output, "chorus_negentropy_sessions {open}");
+
+    header(
+        &mut output,
+        "chorus_negentropy_sessions_total",
+        "counter",
+        "Negentropy sync sessions opened",
+    );
+    let _ = writeln!(
+        output,
+        "chorus_negentropy_sessions_total {}",
+        stats.negentropy_sessions.load(Ordering::Relaxed)
+    );
+
+    if let Some(store) = GLOBALS.store.get() {
+        if let Ok(store_stats) = store.stats() {
+            header(
+                &mut output,
+                "chorus_store_events",
+                "gauge",
+                "Events in the store",
+            );
+            let _ = writeln!(
+                output,
+                "chorus_store_events {}",
+                store_stats.index_stats.i_index_entries
+            );
+            header(
+                &mut output,
+                "chorus_store_event_bytes",
+                "gauge",
+                "Bytes of event data in the store",
+            );
+            let _ = writeln!(
+                output,
+                "chorus_store_event_bytes {}",
+                store_stats.event_bytes
+            );
+            header(
+                &mut output,
+                "chorus_store_index_bytes",
+                "gauge",
+                "Bytes used by the store indexes",
+            );
+            let _ = writeln!(
+                output,
+                "chorus_store_index_bytes {}",
+                store_stats.index_stats.disk_usage
+            );
+        }
+    }
+
+    output
+}
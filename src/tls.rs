@@ -27,9 +27,12 @@ pub fn tls_acceptor(config: &Config) -> Result<TlsAcceptor, Error> {
         None => return Err(ChorusError::NoPrivateKey.into()),
     };
 
-    let tls_config = rustls::ServerConfig::builder()
+    let mut tls_config = rustls::ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(certificates, key)?;
 
+    // Offer HTTP/2, falling back to HTTP/1.1
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
     Ok(TlsAcceptor::from(Arc::new(tls_config)))
 }
@@ -1,9 +1,10 @@
 use crate::error::{ChorusError, Error};
+use crate::roles::Permission;
 use base64::prelude::*;
 use http::header::AUTHORIZATION;
 use hyper::body::Incoming;
 use hyper::Request;
-use pocket_types::Event;
+use pocket_types::{Event, Id, Pubkey, Time};
 
 fn s_err(s: &str) -> Result<AuthData, Error> {
     Err(ChorusError::BlossomAuthFailure(s.to_owned()).into())
@@ -19,11 +20,56 @@ pub enum AuthVerb {
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AuthData {
+    /// The pubkey that signed the authorization event
+    pub pubkey: Pubkey,
+
     /// If a verb was included, this is it
     pub verb: Option<AuthVerb>,
 
     /// If an 'x' tag was included, this is the hash
     pub hash: Option<[u8; 32]>,
+
+    /// The authorization event id
+    pub id: Id,
+
+    /// When the authorization event was created
+    pub created_at: Time,
+
+    /// When the authorization event expires
+    pub expiration: Time,
+}
+
+/// How long after it was created a delete authorization may be used. Used delete
+/// authorizations are remembered (to reject replays) only this long.
+const MAX_DELETE_AUTH_AGE: u64 = 600;
+
+/// Check that a delete authorization covers this blob and has not been used before,
+/// then mark it as used. Call this once the request is otherwise fully validated.
+pub fn use_delete_auth(auth_data: &AuthData, hash: &[u8]) -> Result<(), Error> {
+    let fail = |s: &str| -> Result<(), Error> {
+        Err(ChorusError::BlossomAuthFailure(s.to_owned()).into())
+    };
+
+    if auth_data.verb != Some(AuthVerb::Delete) {
+        return fail("Delete was not authorized");
+    }
+    if auth_data.hash.as_ref().map(|h| h.as_slice()) != Some(hash) {
+        return fail("Authorization event x tag does not match the blob");
+    }
+
+    let forget_after = auth_data.created_at.as_u64() + MAX_DELETE_AUTH_AGE;
+    if Time::now().as_u64() > forget_after {
+        return fail("Delete authorization is too old");
+    }
+
+    // Destructive actions must not be replayed
+    if !crate::use_auth_event(
+        auth_data.id,
+        forget_after.min(auth_data.expiration.as_u64()),
+    ) {
+        return fail("Authorization event has already been used");
+    }
+    Ok(())
 }
 
 pub fn verify_auth(request: &Request<Incoming>) -> Result<AuthData, Error> {
@@ -66,8 +112,8 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
         return s_err(&format!("Authorization event is invalid: {}", e));
     }
 
-    // Nostr event must be signed by a chorus user
-    if !crate::is_authorized_user(event.pubkey()) {
+    // Nostr event must be signed by a user permitted to use blossom
+    if !crate::has_permission(event.pubkey(), Permission::BlossomUpload) {
         return s_err("You are not an authorized user");
     }
 
@@ -77,7 +123,6 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
     }
 
     // Event created_at must be in the past (we give 30 seconds leeway)
-    use pocket_types::Time;
     let now = Time::now();
     if event.created_at() > now + 30 {
         return s_err("Authorization event too far in the future");
@@ -86,15 +131,16 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
     let tags = event.tags()?;
 
     // Expiration tag must be in the future
-    if let Some(v) = tags.get_value(b"expiration") {
+    let expiration = if let Some(v) = tags.get_value(b"expiration") {
         let u = parse_u64(v)?;
         let expiration = Time::from_u64(u);
         if expiration < now {
             return s_err("Authorization event has expired");
         }
+        expiration
     } else {
         return s_err("Authorization event missing expiration tag");
-    }
+    };
 
     // We let the caller check the verb and hash since those are specific
     // to the endpoint (and the 'x' must be checked later on)
@@ -124,7 +170,14 @@ fn verify_auth_inner(request: &Request<Incoming>) -> Result<AuthData, Error> {
         None
     };
 
-    Ok(AuthData { verb, hash })
+    Ok(AuthData {
+        pubkey: event.pubkey(),
+        verb,
+        hash,
+        id: event.id(),
+        created_at: event.created_at(),
+        expiration,
+    })
 }
 
 // FIXME, expose these from pocket-types
@@ -175,14 +175,10 @@ pub async fn handle_hash(
         }
         Method::DELETE => {
             let auth_data = verify_auth(&request)?;
-            if auth_data.verb != Some(AuthVerb::Delete) {
-                return Err(ChorusError::BlossomAuthFailure(
-                    "Delete was not authorized".to_string(),
-                )
-                .into());
-            }
+            auth::use_delete_auth(&auth_data, hash.as_slice())?;
 
             GLOBALS.filestore.get().unwrap().delete(hash).await?;
+            crate::remove_blob_uploads_of_hash(hash)?;
             Ok(Response::builder()
                 .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
                 .header(CONTENT_LENGTH, "0")
@@ -247,6 +243,9 @@ pub async fn handle_upload(
                 )
                 .await?;
 
+            // Remember who uploaded it
+            crate::record_blob_upload(auth_data.pubkey, hash)?;
+
             let extension = {
                 let mut mime_string: String = "".to_owned();
                 if let Some(ms) = maybe_content_type {
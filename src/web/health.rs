@@ -0,0 +1,61 @@
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Response, StatusCode};
+use serde_json::{json, Value};
+
+fn respond(json: Value, status: StatusCode) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let s: String = serde_json::to_string(&json)?;
+    let response = Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .status(status)
+        .body(Full::new(s.into()).map_err(|e| e.into()).boxed())?;
+    Ok(response)
+}
+
+/// Liveness: if we can answer at all, the process is alive
+pub fn serve_healthz() -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    respond(
+        json!({
+            "status": "ok",
+            "uptime": GLOBALS.start_time.elapsed().as_secs(),
+        }),
+        StatusCode::OK,
+    )
+}
+
+/// Readiness: the store is open, the blossom filestore (if any) is writable, and we
+/// are not shutting down
+pub async fn serve_readyz() -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    let store = GLOBALS.store.get().is_some();
+
+    let filestore = if GLOBALS.config.read().blossom_directory.is_some() {
+        match GLOBALS.filestore.get() {
+            Some(filestore) => Some(filestore.is_writable().await),
+            None => Some(false),
+        }
+    } else {
+        None
+    };
+
+    let shutting_down = *GLOBALS.shutting_down.borrow();
+
+    let ready = store && filestore.unwrap_or(true) && !shutting_down;
+
+    respond(
+        json!({
+            "status": if ready { "ready" } else { "not ready" },
+            "store": store,
+            "filestore": filestore,
+            "shutting_down": shutting_down,
+        }),
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+    )
+}
@@ -55,8 +55,8 @@ pub async fn check_auth(request: Request<Incoming>) -> Result<(Pubkey, Value), E
         return s_err(&format!("Authorization event is invalid: {}", e));
     }
 
-    // Nostr event must be signed by a moderator
-    if !crate::is_moderator(event.pubkey()) {
+    // Nostr event must be signed by somebody with a management permission
+    if !crate::can_manage(event.pubkey()) {
         return s_err("Authorization failed as user is not a moderator");
     }
 
@@ -112,5 +112,11 @@ pub async fn check_auth(request: Request<Incoming>) -> Result<(Pubkey, Value), E
         return s_err("Authorization event payload missing");
     }
 
+    // Authorization event must not have been used before. We only need to remember it
+    // until it would be rejected as too far in the past anyway.
+    if !crate::use_auth_event(event.id(), event.created_at().as_u64() + 60) {
+        return s_err("Authorization event has already been used");
+    }
+
     Ok((event.pubkey(), serde_json::from_slice(&body)?))
 }
@@ -1,12 +1,16 @@
 use crate::error::{ChorusError, Error};
+use crate::export::{ImportMode, ModerationState};
 use crate::globals::GLOBALS;
-use crate::ip::HashedPeer;
+use crate::ip::{parse_hashed_ip, HashedIp, HashedPeer, IpBlock};
+use crate::roles::{self, Permission, PermissionSet, BUILTIN_ROLES};
+use crate::stats::BanType;
+use crate::ListFilter;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response, StatusCode};
+use hyper::{Request, Response, StatusCode, Uri};
 use pocket_db::ScreenResult;
-use pocket_types::{Event, Filter, Id, Kind, Pubkey};
+use pocket_types::{Event, Filter, Id, Kind, Pubkey, Time};
 use serde::Serialize;
 use serde_json::{json, Map, Value};
 mod auth;
@@ -16,6 +20,8 @@ struct EventResult {
     id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -30,6 +36,36 @@ struct PubkeyResult {
     pubkey: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+    item: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RoleResult {
+    name: String,
+    permissions: Vec<&'static str>,
+    builtin: bool,
+}
+
+#[derive(Serialize)]
+struct IpResult {
+    ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix_len: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
 }
 
 fn respond(
@@ -66,44 +102,120 @@ pub async fn handle(
         }
     };
 
-    match handle_inner(pubkey, command) {
-        Ok(Some(value)) => respond(value, StatusCode::OK),
-        Ok(None) => {
-            let result = json!({
+    let (result, status) = run_command(pubkey, crate::get_permissions(pubkey), command);
+    respond(result, status)
+}
+
+/// Run a management command with the caller's permissions, returning the response
+/// and the matching HTTP status
+pub fn run_command(
+    pubkey: Pubkey,
+    permissions: PermissionSet,
+    command: Value,
+) -> (Value, StatusCode) {
+    match handle_inner(pubkey, permissions, command) {
+        Ok(Some(value)) => (value, StatusCode::OK),
+        Ok(None) => (
+            json!({
                 "result": {},
-            });
-            respond(result, StatusCode::OK)
+            }),
+            StatusCode::OK,
+        ),
+        Err(e) => match e.inner {
+            ChorusError::BadRequest(s) => (
+                json!({
+                    "result": {},
+                    "error": format!("{}", s)
+                }),
+                StatusCode::BAD_REQUEST,
+            ),
+            ChorusError::NotImplemented => (
+                json!({
+                    "result": {},
+                    "error": "not_implemented"
+                }),
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            _ => (
+                json!({
+                    "result": {},
+                    "error": format!("{}", e)
+                }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        },
+    }
+}
+
+/// Would this event show up in the moderation queue?
+pub fn needs_moderation(event: &Event) -> bool {
+    unmoderated_kind_and_author(event)
+        && !matches!(crate::get_pubkey_approval(event.pubkey()), Ok(Some(_)))
+        && !matches!(crate::get_event_approval(event.id()), Ok(Some(_)))
+}
+
+// Events that are not acceptable on account of their kind or author alone
+fn unmoderated_kind_and_author(e: &Event) -> bool {
+    let allowed_kinds = [
+        Kind::from(4),     // Encrypted Direct Message
+        Kind::from(1059),  // Giftwrap
+        Kind::from(10002), // Relay list
+        Kind::from(10050), // DM Relay list
+        Kind::from(0),     // Metadata
+        Kind::from(3),     // Following list
+        Kind::from(7),     // Reaction
+    ];
+    !(allowed_kinds.contains(&e.kind())
+        || e.kind().is_ephemeral()
+        || crate::has_permission(e.pubkey(), Permission::Write))
+}
+
+/// Advertise an event to moderators listening over websocket
+pub fn notify_event(notification_type: &str, event: &Event) {
+    if GLOBALS.management_notifications.receiver_count() == 0 {
+        return;
+    }
+    let _ = GLOBALS.management_notifications.send(format!(
+        r#"["MANAGE-NOTIFY",{{"type":"{notification_type}","event":{event}}}]"#
+    ));
+}
+
+/// A stats notification for moderators listening over websocket
+pub fn stats_notification() -> String {
+    let notification = json!([
+        "MANAGE-NOTIFY",
+        {
+            "type": "stats",
+            "uptime": GLOBALS.start_time.elapsed().as_secs(),
+            "num_connections": &GLOBALS.num_connections,
+            "bytes_received": &GLOBALS.bytes_inbound,
+            "bytes_sent": &GLOBALS.bytes_outbound,
         }
-        Err(e) => {
-            let (result, status) = match e.inner {
-                ChorusError::BadRequest(s) => (
-                    json!({
-                        "result": {},
-                        "error": format!("{}", s)
-                    }),
-                    StatusCode::BAD_REQUEST,
-                ),
-                ChorusError::NotImplemented => (
-                    json!({
-                        "result": {},
-                        "error": "not_implemented"
-                    }),
-                    StatusCode::NOT_IMPLEMENTED,
-                ),
-                _ => (
-                    json!({
-                        "result": {},
-                        "error": format!("{}", e)
-                    }),
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                ),
-            };
-            respond(result, status)
+    ]);
+    format!("{notification}")
+}
+
+// The permission needed to run a management method. Methods not listed here
+// only require that the caller may use the management API at all.
+fn required_permission(method: &str) -> Option<Permission> {
+    match method {
+        "allowevent" | "banevent" | "clearevent" | "allowpubkey" | "banpubkey" | "clearpubkey"
+        | "allowevents" | "banevents" | "clearevents" | "allowpubkeys" | "banpubkeys"
+        | "clearpubkeys" | "blockip" | "unblockip" | "kickconnection" | "kickpubkey" => {
+            Some(Permission::CanBan)
         }
+        "removeevent" | "removeevents" | "canceljob" => Some(Permission::CanDelete),
+        "grantmoderator" | "revokemoderator" | "grantuser" | "revokeuser" | "listrole"
+        | "grantrole" | "revokerole" => Some(Permission::CanManageUsers),
+        _ => None,
     }
 }
 
-pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Error> {
+pub fn handle_inner(
+    pubkey: Pubkey,
+    permissions: PermissionSet,
+    command: Value,
+) -> Result<Option<Value>, Error> {
     let obj = match command.as_object() {
         Some(o) => o,
         None => return Err(ChorusError::BadRequest("Command was not a JSON object").into()),
@@ -117,12 +229,22 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         None => return Err(ChorusError::BadRequest("Method missing").into()),
     };
 
+    if let Some(permission) = required_permission(&method) {
+        if !permissions.contains(permission) {
+            return Ok(Some(json!({
+                "result": {},
+                "error": format!("Unauthorized: {permission} permission required")
+            })));
+        }
+    }
+
     match &*method {
         "supportedmethods" => Ok(Some(json!({
             "result": [
                 "supportedmethods",
 
                 "listeventsneedingmoderation",
+                "counteventsneedingmoderation",
 
                 "allowevent",
                 "banevent",
@@ -133,58 +255,86 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "banpubkey",
                 "clearpubkey",
 
+                "allowevents",
+                "banevents",
+                "clearevents",
+                "removeevents",
+                "allowpubkeys",
+                "banpubkeys",
+                "clearpubkeys",
+
                 "listallowedevents",
                 "listbannedevents",
                 "fetchbannedevents",
                 "listallowedpubkeys",
                 "listbannedpubkeys",
+                "countallowedevents",
+                "countbannedevents",
+                "countallowedpubkeys",
+                "countbannedpubkeys",
+
+                "blockip",
+                "unblockip",
+                "listblockedips",
+                "countblockedips",
+
+                "listjobs",
+                "jobstatus",
+                "canceljob",
+
+                "changerelayname",
+                "changerelaydescription",
+                "changerelayicon",
+                "changerelaybanner",
 
                 "stats",
                 "numconnections",
+                "listconnections",
+                "kickconnection",
+                "kickpubkey",
                 "uptime",
 
                 "listadmins",
                 "listmoderators",
+                "countmoderators",
                 "grantmoderator",
                 "revokemoderator",
                 "listusers",
+                "countusers",
                 "grantuser",
                 "revokeuser",
                 "listrole",
                 "grantrole",
                 "revokerole",
+                "listroles",
+                "definerole",
+                "deleterole",
+
+                "exportstate",
+                "importstate",
             ]
         }))),
-        "listeventsneedingmoderation" => {
+        "listeventsneedingmoderation" | "counteventsneedingmoderation" => {
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+
             // FIXME this scans the entire database, maybe we need to some process
             // that does this in epochs and saves the result.
 
-            let allowed_kinds = [
-                Kind::from(4),     // Encrypted Direct Message
-                Kind::from(1059),  // Giftwrap
-                Kind::from(10002), // Relay list
-                Kind::from(10050), // DM Relay list
-                Kind::from(0),     // Metadata
-                Kind::from(3),     // Following list
-                Kind::from(7),     // Reaction
-            ];
             let mut buffer: [u8; 128] = [0; 128];
             let filter = {
                 let (_incount, _outcount, filter) = Filter::from_json(b"{}", &mut buffer)?;
                 filter
             };
             let screen = |e: &Event| -> ScreenResult {
-                if allowed_kinds.contains(&e.kind())
-                    || e.kind().is_ephemeral()
-                    || crate::is_authorized_user(e.pubkey())
-                {
-                    ScreenResult::Mismatch
-                } else {
+                if unmoderated_kind_and_author(e) {
                     ScreenResult::Match
+                } else {
+                    ScreenResult::Mismatch
                 }
             };
 
-            let mut need_moderation: Vec<EventResult> = Vec::new();
+            let mut need_moderation: Vec<(String, Option<u64>, Option<String>, EventResult)> =
+                Vec::new();
 
             let (mut events, _redacted) = GLOBALS
                 .store
@@ -203,24 +353,36 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                     continue;
                 }
 
-                need_moderation.push(EventResult {
-                    id: event.id().as_hex_string(),
-                    reason: Some("unmoderated".to_string()),
-                });
+                let id = event.id().as_hex_string();
+                let created_at = event.created_at().as_u64();
+                need_moderation.push((
+                    id.clone(),
+                    Some(created_at),
+                    Some("unmoderated".to_string()),
+                    EventResult {
+                        id,
+                        reason: Some("unmoderated".to_string()),
+                        created_at: None,
+                    },
+                ));
             }
 
-            Ok(Some(json!({
-                "result": need_moderation,
-            })))
+            list_or_count(
+                &method,
+                filter_in_memory(need_moderation, &list_filter, true),
+            )
         }
         "allowevent" => {
             let id = get_id_param(obj)?;
-            crate::mark_event_approval(id, true)?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            crate::mark_event_approval_with_reason(id, true, &reason)?;
             Ok(None)
         }
         "banevent" => {
             let id = get_id_param(obj)?;
-            crate::mark_event_approval(id, false)?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            crate::mark_event_approval_with_reason(id, false, &reason)?;
+            GLOBALS.stats.count_ban(BanType::Event);
             Ok(None)
         }
         "clearevent" => {
@@ -236,13 +398,35 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
 
         "allowpubkey" => {
             let pk = get_pubkey_param(obj)?;
-            crate::mark_pubkey_approval(pk, true)?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            crate::mark_pubkey_approval_with_reason(pk, true, &reason)?;
             Ok(None)
         }
         "banpubkey" => {
             let pk = get_pubkey_param(obj)?;
-            crate::mark_pubkey_approval(pk, false)?;
-            Ok(None)
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            let purge = get_optional_bool_param(obj, 2)?.unwrap_or(false);
+            if purge && !permissions.contains(Permission::CanDelete) {
+                return Ok(Some(json!({
+                    "result": {},
+                    "error": "Unauthorized: can-delete permission required to purge"
+                })));
+            }
+
+            crate::mark_pubkey_approval_with_reason(pk, false, &reason)?;
+            GLOBALS.stats.count_ban(BanType::Pubkey);
+
+            // Optionally remove everything they already stored, in the background
+            if purge {
+                let job = crate::jobs::start_purge_pubkey(pk);
+                Ok(Some(json!({
+                    "result": {
+                        "job": job
+                    }
+                })))
+            } else {
+                Ok(None)
+            }
         }
         "clearpubkey" => {
             let pk = get_pubkey_param(obj)?;
@@ -250,98 +434,228 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
             Ok(None)
         }
 
-        "listallowedevents" => {
-            let approvals = crate::dump_event_approvals()?;
-            let ids: Vec<EventResult> = approvals
-                .iter()
-                .filter_map(|(id, appr)| {
-                    if *appr {
-                        Some(EventResult {
-                            id: id.as_hex_string(),
-                            reason: None,
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+        "allowevents" | "banevents" | "clearevents" => {
+            let (ids, mut results) = get_batch_param(obj, |s| Id::read_hex(s.as_bytes()).ok())?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            let approval = match &*method {
+                "allowevents" => Some(true),
+                "banevents" => Some(false),
+                _ => None,
+            };
+            let outcome = crate::batch_event_approval(&ids, approval, &reason);
+            if outcome.is_ok() && approval == Some(false) {
+                for _ in ids.iter() {
+                    GLOBALS.stats.count_ban(BanType::Event);
+                }
+            }
+            finish_batch(&mut results, &outcome);
             Ok(Some(json!({
-                "result": ids
+                "result": results
             })))
         }
-        "listbannedevents" => {
-            let approvals = crate::dump_event_approvals()?;
-            let ids: Vec<EventResult> = approvals
-                .iter()
-                .filter_map(|(id, appr)| {
-                    if *appr {
-                        None
-                    } else {
-                        Some(EventResult {
-                            id: id.as_hex_string(),
-                            reason: None,
-                        })
-                    }
-                })
-                .collect();
+        "removeevents" => {
+            let (ids, mut results) = get_batch_param(obj, |s| Id::read_hex(s.as_bytes()).ok())?;
+            let mut outcomes = crate::remove_events(&ids).into_iter();
+            for result in results.iter_mut().filter(|r| r.error.is_none()) {
+                match outcomes.next() {
+                    Some(Ok(true)) => result.ok = true,
+                    Some(Ok(false)) => result.error = Some("not found".to_owned()),
+                    Some(Err(e)) => result.error = Some(format!("{e}")),
+                    None => {}
+                }
+            }
             Ok(Some(json!({
-                "result": ids
+                "result": results
             })))
         }
-        "fetchbannedevents" => {
-            let approvals = crate::dump_event_approvals()?;
-            let mut results: Vec<FullEventResult> = Vec::new();
-            for (id, appr) in approvals.iter() {
-                if !*appr {
-                    if let Some(event) = GLOBALS.store.get().unwrap().get_event_by_id(*id)? {
-                        results.push(FullEventResult {
-                            event: format!("{event}"),
-                            reason: None,
-                        });
-                    }
+        "allowpubkeys" | "banpubkeys" | "clearpubkeys" => {
+            let (pubkeys, mut results) =
+                get_batch_param(obj, |s| Pubkey::read_hex(s.as_bytes()).ok())?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            let approval = match &*method {
+                "allowpubkeys" => Some(true),
+                "banpubkeys" => Some(false),
+                _ => None,
+            };
+            let outcome = crate::batch_pubkey_approval(&pubkeys, approval, &reason);
+            if outcome.is_ok() && approval == Some(false) {
+                for _ in pubkeys.iter() {
+                    GLOBALS.stats.count_ban(BanType::Pubkey);
                 }
             }
+            finish_batch(&mut results, &outcome);
             Ok(Some(json!({
                 "result": results
             })))
         }
-        "listallowedpubkeys" => {
-            let approvals = crate::dump_pubkey_approvals()?;
-            let pubkeys: Vec<PubkeyResult> = approvals
-                .iter()
-                .filter_map(|(pk, appr)| {
-                    if *appr {
-                        Some(PubkeyResult {
-                            pubkey: pk.as_hex_string(),
-                            reason: None,
-                        })
-                    } else {
-                        None
-                    }
+
+        "listallowedevents" | "countallowedevents" | "listbannedevents" | "countbannedevents" => {
+            let approved = method.ends_with("allowedevents");
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let ids: Vec<EventResult> = crate::list_event_approvals(approved, &list_filter)?
+                .drain(..)
+                .map(|(id, approval)| EventResult {
+                    id: id.as_hex_string(),
+                    reason: approval.reason,
+                    created_at: approval.created_at,
                 })
                 .collect();
+            list_or_count(&method, ids)
+        }
+        "fetchbannedevents" => {
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let mut results: Vec<FullEventResult> = Vec::new();
+            for (id, approval) in crate::list_event_approvals(false, &list_filter)?.drain(..) {
+                if let Some(event) = GLOBALS.store.get().unwrap().get_event_by_id(id)? {
+                    results.push(FullEventResult {
+                        event: format!("{event}"),
+                        reason: approval.reason,
+                    });
+                }
+            }
             Ok(Some(json!({
-                "result": pubkeys
+                "result": results
             })))
         }
-        "listbannedpubkeys" => {
-            let approvals = crate::dump_pubkey_approvals()?;
-            let pubkeys: Vec<PubkeyResult> = approvals
-                .iter()
-                .filter_map(|(pk, appr)| {
-                    if *appr {
-                        None
-                    } else {
-                        Some(PubkeyResult {
-                            pubkey: pk.as_hex_string(),
-                            reason: None,
-                        })
-                    }
+        "listallowedpubkeys"
+        | "countallowedpubkeys"
+        | "listbannedpubkeys"
+        | "countbannedpubkeys" => {
+            let approved = method.ends_with("allowedpubkeys");
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let pubkeys: Vec<PubkeyResult> = crate::list_pubkey_approvals(approved, &list_filter)?
+                .drain(..)
+                .map(|(pk, approval)| PubkeyResult {
+                    pubkey: pk.as_hex_string(),
+                    reason: approval.reason,
+                    created_at: approval.created_at,
                 })
                 .collect();
-            Ok(Some(json!({
-                "result": pubkeys
-            })))
+            list_or_count(&method, pubkeys)
+        }
+
+        "blockip" => {
+            let (ips, prefix_len) = get_ip_param(obj)?;
+            let reason = get_optional_string_param(obj, 1)?.unwrap_or_default();
+            // Addresses are hashed with the current key, but a hash from our logs may
+            // have been made with any of them
+            let key_created_at = match HashedIp::from_tag(&get_string_param(obj)?) {
+                Some(_) => None,
+                None => Some(crate::ip::ip_hash_keys()[0].created_at),
+            };
+            let block = IpBlock {
+                reason,
+                created_at: Time::now().as_u64(),
+                prefix_len,
+                key_created_at,
+            };
+            crate::block_ip(ips[0], &block)?;
+            GLOBALS.stats.count_ban(BanType::Ip);
+            Ok(None)
+        }
+        "unblockip" => {
+            let (ips, _prefix_len) = get_ip_param(obj)?;
+            for ip in ips {
+                crate::unblock_ip(ip)?;
+
+                // Also lift any temporary ban from the IP's reputation
+                let mut ip_data = crate::get_ip_data(ip)?;
+                if ip_data.is_banned() {
+                    ip_data.ban_until = 0;
+                    crate::update_ip_data(ip, &ip_data)?;
+                }
+            }
+            Ok(None)
+        }
+        "listblockedips" | "countblockedips" => {
+            let list_filter = get_list_filter(obj, &method, 0, false)?;
+            let mut ips: Vec<(String, Option<u64>, Option<String>, IpResult)> =
+                crate::dump_ip_blocks()?
+                    .drain(..)
+                    .map(|(ip, block)| {
+                        let reason = if block.reason.is_empty() {
+                            None
+                        } else {
+                            Some(block.reason)
+                        };
+                        (
+                            format!("{ip}"),
+                            Some(block.created_at),
+                            reason.clone(),
+                            IpResult {
+                                ip: format!("{ip}"),
+                                reason,
+                                prefix_len: block.prefix_len,
+                                until: None,
+                                created_at: Some(block.created_at),
+                            },
+                        )
+                    })
+                    .collect();
+
+            // Include temporary bans from IP reputation
+            for (ip, ip_data) in crate::dump_ip_data()?.drain(..) {
+                if ip_data.is_banned() {
+                    let reason = Some("temporary ban".to_string());
+                    ips.push((
+                        format!("{ip}"),
+                        None,
+                        reason.clone(),
+                        IpResult {
+                            ip: format!("{ip}"),
+                            reason,
+                            prefix_len: None,
+                            until: Some(ip_data.ban_until),
+                            created_at: None,
+                        },
+                    ));
+                }
+            }
+
+            list_or_count(&method, filter_in_memory(ips, &list_filter, false))
+        }
+
+        "listjobs" => Ok(Some(json!({
+            "result": crate::jobs::list_jobs()
+        }))),
+        "jobstatus" => {
+            let id = get_u64_param(obj)?;
+            match crate::jobs::get_job(id) {
+                Some(job) => Ok(Some(json!({
+                    "result": job.report()
+                }))),
+                None => Err(ChorusError::BadRequest("No such job").into()),
+            }
+        }
+        "canceljob" => {
+            let id = get_u64_param(obj)?;
+            match crate::jobs::get_job(id) {
+                Some(job) => {
+                    job.cancel();
+                    Ok(None)
+                }
+                None => Err(ChorusError::BadRequest("No such job").into()),
+            }
+        }
+
+        "changerelayname" | "changerelaydescription" | "changerelayicon" | "changerelaybanner" => {
+            if !crate::is_admin(pubkey) {
+                Ok(Some(json!({
+                    "result": {},
+                    "error": "Unauthorized: Only admins can change relay information"
+                })))
+            } else {
+                let value = get_string_param(obj)?;
+                let field = &method["changerelay".len()..];
+                if (field == "icon" || field == "banner") && !value.is_empty() {
+                    match value.parse::<Uri>() {
+                        Ok(uri) if matches!(uri.scheme_str(), Some("http") | Some("https")) => (),
+                        _ => return Err(ChorusError::BadRequest("Invalid URL").into()),
+                    }
+                }
+                crate::set_relay_info(field, &value)?;
+                Ok(None)
+            }
         }
 
         "stats" => {
@@ -352,10 +666,13 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                     "num_connections": &GLOBALS.num_connections,
                     "bytes_received": &GLOBALS.bytes_inbound,
                     "bytes_sent": &GLOBALS.bytes_outbound,
+                    "bytes_received_uncompressed": &GLOBALS.bytes_inbound_uncompressed,
+                    "bytes_sent_uncompressed": &GLOBALS.bytes_outbound_uncompressed,
                     "event_bytes": store_stats.event_bytes,
                     "num_events": store_stats.index_stats.i_index_entries,
                     "index_disk_usage": store_stats.index_stats.disk_usage,
                     "index_memory_usage": store_stats.index_stats.memory_usage,
+                    "events": GLOBALS.stats.event_report(),
                 }
             })))
         }
@@ -365,6 +682,22 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
                 "result": num,
             })))
         }
+        "listconnections" => Ok(Some(json!({
+            "result": crate::session::list_sessions()
+        }))),
+        "kickconnection" => {
+            let id = get_u64_param(obj)?;
+            if !crate::session::kick_session(id) {
+                return Err(ChorusError::BadRequest("No such connection").into());
+            }
+            Ok(None)
+        }
+        "kickpubkey" => {
+            let pk = get_pubkey_param(obj)?;
+            Ok(Some(json!({
+                "result": { "kicked": crate::session::kick_pubkey(pk) }
+            })))
+        }
         "uptime" => {
             let uptime_in_secs = GLOBALS.start_time.elapsed().as_secs();
             Ok(Some(json!({
@@ -373,199 +706,302 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
         }
 
         "listadmins" => {
-            let keys = GLOBALS.config.read().admin_hex_keys.clone();
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let keys = filter_keys_in_memory(admin_hex_keys()?, &list_filter);
             Ok(Some(json!({
                 "result": keys
             })))
         }
-        "listmoderators" => {
-            let moderators: Vec<String> = crate::dump_authorized_users()?
+        "listmoderators" | "countmoderators" => {
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let moderators: Vec<String> = crate::list_authorized_users(true, &list_filter)?
                 .iter()
-                .filter_map(|(pk, moderator)| {
-                    if *moderator {
-                        Some(pk.as_hex_string())
-                    } else {
-                        None
-                    }
-                })
+                .map(|(pk, _moderator)| pk.as_hex_string())
                 .collect();
-            Ok(Some(json!({
-                "result": moderators
-            })))
+            list_or_count(&method, moderators)
         }
         "grantmoderator" => {
-            if !crate::is_admin(pubkey) {
-                Ok(Some(json!({
-                    "result": {},
-                    "error": "Unauthorized: Only admins can grant moderator status"
-                })))
-            } else {
-                let pk = get_pubkey_param(obj)?;
-                crate::add_authorized_user(pk, true)?;
-                Ok(None)
+            let pk = get_pubkey_param(obj)?;
+            if exceeds_own_permissions(
+                permissions,
+                roles::builtin_role("moderator").unwrap_or_default(),
+            ) {
+                return unauthorized_role();
             }
+            crate::add_authorized_user(pk, true)?;
+            Ok(None)
         }
         "revokemoderator" => {
-            if !crate::is_admin(pubkey) {
-                Ok(Some(json!({
-                    "result": {},
-                    "error": "Unauthorized: Only admins can revoke moderator status"
-                })))
-            } else {
-                let pk = get_pubkey_param(obj)?;
+            let pk = get_pubkey_param(obj)?;
+            if exceeds_own_permissions(
+                permissions,
+                roles::builtin_role("moderator").unwrap_or_default(),
+            ) {
+                return unauthorized_role();
+            }
 
-                // Do not do this if they aren't already an authorized user
-                if !crate::is_authorized_user(pk) {
-                    Ok(None)
-                } else {
+            // Do not do this if they aren't already an authorized user
+            if !crate::is_authorized_user(pk) {
+                Ok(None)
+            } else {
+                crate::add_authorized_user(pk, false)?;
+                Ok(None)
+            }
+        }
+        "listusers" | "countusers" => {
+            let list_filter = get_list_filter(obj, &method, 0, true)?;
+            let users: Vec<String> = crate::list_authorized_users(false, &list_filter)?
+                .iter()
+                .map(|(pk, _moderator)| pk.as_hex_string())
+                .collect();
+            list_or_count(&method, users)
+        }
+        "grantuser" => {
+            let pk = get_pubkey_param(obj)?;
+            if exceeds_own_permissions(permissions, roles::builtin_role("user").unwrap_or_default())
+            {
+                return unauthorized_role();
+            }
+            crate::add_authorized_user(pk, false)?;
+            Ok(None)
+        }
+        "revokeuser" => {
+            let pk = get_pubkey_param(obj)?;
+            if exceeds_own_permissions(permissions, roles::builtin_role("user").unwrap_or_default())
+            {
+                return unauthorized_role();
+            }
+            crate::rm_authorized_user(pk)?;
+            Ok(None)
+        }
+        "listrole" => {
+            let role = get_string_param(obj)?;
+            let list_filter = get_list_filter(obj, &method, 1, true)?;
+            let pubkeys: Vec<String> = match &*role {
+                "admin" => filter_keys_in_memory(admin_hex_keys()?, &list_filter),
+                "user" => crate::list_authorized_users(false, &list_filter)?
+                    .iter()
+                    .map(|(pk, _moderator)| pk.as_hex_string())
+                    .collect(),
+                "moderator" => crate::list_authorized_users(true, &list_filter)?
+                    .iter()
+                    .map(|(pk, _moderator)| pk.as_hex_string())
+                    .collect(),
+                custom => {
+                    if crate::get_role(custom)?.is_none() {
+                        return Ok(Some(json!({
+                            "result": {},
+                            "error": "Unknown role."
+                        })));
+                    }
+                    let keys = crate::dump_user_roles()?
+                        .iter()
+                        .filter(|(_pk, name)| name == custom)
+                        .map(|(pk, _name)| pk.as_hex_string())
+                        .collect();
+                    filter_keys_in_memory(keys, &list_filter)
+                }
+            };
+            Ok(Some(json!({
+                "result": pubkeys
+            })))
+        }
+        "grantrole" => {
+            let pk = get_pubkey_param(obj)?;
+            let role = get_optional_string_param(obj, 1)?
+                .ok_or(ChorusError::BadRequest("Missing role parameter").into_err())?;
+            match &*role {
+                "admin" => {
+                    if !crate::is_admin(pubkey) {
+                        Ok(Some(json!({
+                            "result": {},
+                            "error": "Unauthorized: Only admins can grant admin status"
+                        })))
+                    } else {
+                        crate::add_admin(pk)?;
+                        Ok(None)
+                    }
+                }
+                "user" | "moderator"
+                    if exceeds_own_permissions(
+                        permissions,
+                        roles::builtin_role(&role).unwrap_or_default(),
+                    ) =>
+                {
+                    unauthorized_role()
+                }
+                "user" => {
                     crate::add_authorized_user(pk, false)?;
                     Ok(None)
                 }
+                "moderator" => {
+                    crate::add_authorized_user(pk, true)?;
+                    Ok(None)
+                }
+                custom => match crate::get_role(custom)? {
+                    None => Ok(Some(json!({
+                        "result": {},
+                        "error": "Unknown role."
+                    }))),
+                    Some(role_permissions)
+                        if exceeds_own_permissions(permissions, role_permissions) =>
+                    {
+                        unauthorized_role()
+                    }
+                    Some(_) => {
+                        crate::grant_role(pk, custom)?;
+                        Ok(None)
+                    }
+                },
             }
         }
-        "listusers" => {
-            let users: Vec<String> = crate::dump_authorized_users()?
+        "revokerole" => {
+            let pk = get_pubkey_param(obj)?;
+            let role = get_optional_string_param(obj, 1)?
+                .ok_or(ChorusError::BadRequest("Missing role parameter").into_err())?;
+            match &*role {
+                "admin" => {
+                    if !crate::is_admin(pubkey) {
+                        Ok(Some(json!({
+                            "result": {},
+                            "error": "Unauthorized: Only admins can revoke admin status"
+                        })))
+                    } else if crate::is_root_admin(pk) {
+                        Ok(Some(json!({
+                            "result": {},
+                            "error": "Admins in the config file cannot be revoked via this interface."
+                        })))
+                    } else {
+                        crate::rm_admin(pk)?;
+                        Ok(None)
+                    }
+                }
+                "user" | "moderator"
+                    if exceeds_own_permissions(
+                        permissions,
+                        roles::builtin_role(&role).unwrap_or_default(),
+                    ) =>
+                {
+                    unauthorized_role()
+                }
+                "user" => {
+                    crate::rm_authorized_user(pk)?;
+                    Ok(None)
+                }
+                "moderator" => {
+                    // Do not do this if they aren't already an authorized user
+                    if !crate::is_authorized_user(pk) {
+                        Ok(None)
+                    } else {
+                        crate::add_authorized_user(pk, false)?;
+                        Ok(None)
+                    }
+                }
+                custom => match crate::get_role(custom)? {
+                    Some(role_permissions)
+                        if exceeds_own_permissions(permissions, role_permissions) =>
+                    {
+                        unauthorized_role()
+                    }
+                    _ => {
+                        crate::revoke_role(pk, custom)?;
+                        Ok(None)
+                    }
+                },
+            }
+        }
+        "listroles" => {
+            let mut roles: Vec<RoleResult> = BUILTIN_ROLES
                 .iter()
-                .map(|(pk, _moderator)| pk.as_hex_string())
+                .map(|name| RoleResult {
+                    name: name.to_string(),
+                    permissions: roles::builtin_role(name)
+                        .unwrap_or_default()
+                        .permissions()
+                        .iter()
+                        .map(|p| p.name())
+                        .collect(),
+                    builtin: true,
+                })
                 .collect();
+            for (name, permissions) in crate::dump_roles()?.drain(..) {
+                roles.push(RoleResult {
+                    name,
+                    permissions: permissions.permissions().iter().map(|p| p.name()).collect(),
+                    builtin: false,
+                });
+            }
             Ok(Some(json!({
-                "result": users
+                "result": roles
             })))
         }
-        "grantuser" => {
+        "definerole" => {
             if !crate::is_admin(pubkey) {
                 Ok(Some(json!({
                     "result": {},
-                    "error": "Unauthorized: Only admins can grant user status"
+                    "error": "Unauthorized: Only admins can define roles"
                 })))
             } else {
-                let pk = get_pubkey_param(obj)?;
-                crate::add_authorized_user(pk, false)?;
+                let name = get_string_param(obj)?;
+                if name.is_empty() || BUILTIN_ROLES.contains(&&*name) {
+                    return Err(ChorusError::BadRequest("That role name cannot be defined").into());
+                }
+                let permissions: PermissionSet = get_string_array_param(obj, 1)?
+                    .iter()
+                    .map(|p| p.parse::<Permission>())
+                    .collect::<Result<_, Error>>()?;
+                crate::define_role(&name, permissions)?;
                 Ok(None)
             }
         }
-        "revokeuser" => {
+        "deleterole" => {
             if !crate::is_admin(pubkey) {
                 Ok(Some(json!({
                     "result": {},
-                    "error": "Unauthorized: Only admins can revoke user status"
+                    "error": "Unauthorized: Only admins can delete roles"
                 })))
             } else {
-                let pk = get_pubkey_param(obj)?;
-                crate::rm_authorized_user(pk)?;
+                let name = get_string_param(obj)?;
+                if BUILTIN_ROLES.contains(&&*name) {
+                    return Err(ChorusError::BadRequest("Built-in roles cannot be deleted").into());
+                }
+                crate::delete_role(&name)?;
                 Ok(None)
             }
         }
-        "listrole" => {
+        "exportstate" => {
             if !crate::is_admin(pubkey) {
                 Ok(Some(json!({
                     "result": {},
-                    "error": "Unauthorized: Only admins can revoke user status"
+                    "error": "Unauthorized: Only admins can export moderation state"
                 })))
             } else {
-                let role = get_string_param(obj)?;
-                match &*role {
-                    "admin" => {
-                        let keys = GLOBALS.config.read().admin_hex_keys.clone();
-                        Ok(Some(json!({
-                            "result": keys
-                        })))
-                    }
-                    "user" => {
-                        let users: Vec<String> = crate::dump_authorized_users()?
-                            .iter()
-                            .map(|(pk, _moderator)| pk.as_hex_string())
-                            .collect();
-                        Ok(Some(json!({
-                            "result": users
-                        })))
-                    }
-                    "moderator" => {
-                        let moderators: Vec<String> = crate::dump_authorized_users()?
-                            .iter()
-                            .filter_map(|(pk, moderator)| {
-                                if *moderator {
-                                    Some(pk.as_hex_string())
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-                        Ok(Some(json!({
-                            "result": moderators
-                        })))
-                    }
-                    _ => Ok(Some(json!({
-                        "result": {},
-                        "error": "Unknown role."
-                    }))),
-                }
-            }
-        }
-        "grantrole" => {
-            if !crate::is_admin(pubkey) {
                 Ok(Some(json!({
-                    "result": {},
-                    "error": "Unauthorized: Only admins can grant user status"
+                    "result": crate::export::export_state()?
                 })))
-            } else {
-                let role = get_string_param(obj)?;
-                match &*role {
-                    "admin" => Ok(Some(json!({
-                        "result": {},
-                        "error": "The administrators cannot be managed via this interface."
-                    }))),
-                    "user" => {
-                        let pk = get_pubkey_param(obj)?;
-                        crate::add_authorized_user(pk, false)?;
-                        Ok(None)
-                    }
-                    "moderator" => {
-                        let pk = get_pubkey_param(obj)?;
-                        crate::add_authorized_user(pk, true)?;
-                        Ok(None)
-                    }
-                    _ => Ok(Some(json!({
-                        "result": {},
-                        "error": "Unknown role."
-                    }))),
-                }
             }
         }
-        "revokerole" => {
+        "importstate" => {
             if !crate::is_admin(pubkey) {
                 Ok(Some(json!({
                     "result": {},
-                    "error": "Unauthorized: Only admins can grant user status"
+                    "error": "Unauthorized: Only admins can import moderation state"
                 })))
             } else {
-                let role = get_string_param(obj)?;
-                match &*role {
-                    "admin" => Ok(Some(json!({
-                        "result": {},
-                        "error": "The administrators cannot be managed via this interface."
-                    }))),
-                    "user" => {
-                        let pk = get_pubkey_param(obj)?;
-                        crate::rm_authorized_user(pk)?;
-                        Ok(None)
-                    }
-                    "moderator" => {
-                        let pk = get_pubkey_param(obj)?;
-
-                        // Do not do this if they aren't already an authorized user
-                        if !crate::is_authorized_user(pk) {
-                            Ok(None)
-                        } else {
-                            crate::add_authorized_user(pk, false)?;
-                            Ok(None)
-                        }
-                    }
-                    _ => Ok(Some(json!({
-                        "result": {},
-                        "error": "Unknown role."
-                    }))),
-                }
+                let document = obj
+                    .get("params")
+                    .and_then(|p| p.as_array())
+                    .and_then(|p| p.first())
+                    .ok_or(ChorusError::BadRequest("Missing parameter").into_err())?;
+                let state: ModerationState = serde_json::from_value(document.clone())?;
+                let mode: ImportMode = get_optional_string_param(obj, 1)?
+                    .as_deref()
+                    .unwrap_or("merge")
+                    .parse()?;
+                let count = crate::export::import_state(&state, mode)?;
+                Ok(Some(json!({
+                    "result": { "imported": count }
+                })))
             }
         }
 
@@ -573,6 +1009,192 @@ pub fn handle_inner(pubkey: Pubkey, command: Value) -> Result<Option<Value>, Err
     }
 }
 
+// Read the array of items (ids or pubkeys) for a batch method. Returns the items that
+// parsed along with a result for every item; unparsable items already have an error.
+fn get_batch_param<T, F>(
+    obj: &Map<String, Value>,
+    parse: F,
+) -> Result<(Vec<T>, Vec<BatchResult>), Error>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    let mut items: Vec<T> = Vec::new();
+    let mut results: Vec<BatchResult> = Vec::new();
+    for text in get_string_array_param(obj, 0)?.drain(..) {
+        match parse(&text) {
+            Some(item) => {
+                items.push(item);
+                results.push(BatchResult {
+                    item: text,
+                    ok: false,
+                    error: None,
+                });
+            }
+            None => results.push(BatchResult {
+                item: text,
+                ok: false,
+                error: Some("could not be parsed".to_owned()),
+            }),
+        }
+    }
+    Ok((items, results))
+}
+
+// Record the outcome of a batch transaction against every item that was attempted
+fn finish_batch(results: &mut [BatchResult], outcome: &Result<(), Error>) {
+    for result in results.iter_mut().filter(|r| r.error.is_none()) {
+        match outcome {
+            Ok(()) => result.ok = true,
+            Err(e) => result.error = Some(format!("{e}")),
+        }
+    }
+}
+
+fn list_or_count<T: Serialize>(method: &str, items: Vec<T>) -> Result<Option<Value>, Error> {
+    if method.starts_with("count") {
+        Ok(Some(json!({
+            "result": items.len()
+        })))
+    } else {
+        Ok(Some(json!({
+            "result": items
+        })))
+    }
+}
+
+// Apply a list filter to entries already in memory. Each entry is given with its
+// displayed key, creation time and reason.
+fn filter_in_memory<T>(
+    mut entries: Vec<(String, Option<u64>, Option<String>, T)>,
+    filter: &ListFilter,
+    hex_keys: bool,
+) -> Vec<T> {
+    let after: Option<String> = filter.after.as_ref().map(|a| {
+        if hex_keys {
+            hex::encode(a)
+        } else {
+            String::from_utf8_lossy(a).into_owned()
+        }
+    });
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+        .drain(..)
+        .filter(|(key, _, _, _)| match &after {
+            Some(after) => key > after,
+            None => true,
+        })
+        .filter(|(key, created_at, reason, _)| filter.matches(key, *created_at, reason.as_deref()))
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .map(|(_, _, _, item)| item)
+        .collect()
+}
+
+fn filter_keys_in_memory(keys: Vec<String>, filter: &ListFilter) -> Vec<String> {
+    let entries = keys
+        .into_iter()
+        .map(|k| (k.clone(), None, None, k))
+        .collect();
+    filter_in_memory(entries, filter, true)
+}
+
+// Read the optional list filter object parameter:
+//   {"cursor": <key>, "limit": <n>, "prefix": <key prefix>, "since": <unixtime>, "reason": <text>}
+// If `hex_keys`, the cursor is a hex key as displayed (an id or pubkey). Count methods
+// count every matching entry, so they ignore the cursor and limit.
+fn get_list_filter(
+    obj: &Map<String, Value>,
+    method: &str,
+    index: usize,
+    hex_keys: bool,
+) -> Result<ListFilter, Error> {
+    let options = match obj
+        .get("params")
+        .and_then(|p| p.as_array())
+        .and_then(|p| p.get(index))
+    {
+        None | Some(Value::Null) => return Ok(ListFilter::default()),
+        Some(Value::Object(options)) => options,
+        Some(_) => return Err(ChorusError::BadRequest("List options not an object").into()),
+    };
+
+    let get_str = |field: &str| -> Result<Option<String>, Error> {
+        match options.get(field) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.to_owned())),
+            Some(_) => Err(ChorusError::BadRequest("List option is not a string").into()),
+        }
+    };
+    let get_u64 = |field: &str| -> Result<Option<u64>, Error> {
+        match options.get(field) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => Ok(Some(v.as_u64().ok_or(
+                ChorusError::BadRequest("List option is not a number").into_err(),
+            )?)),
+        }
+    };
+
+    let after = match get_str("cursor")? {
+        None => None,
+        Some(cursor) if hex_keys => Some(
+            hex::decode(&cursor)
+                .map_err(|_| ChorusError::BadRequest("Cursor is not hex").into_err())?,
+        ),
+        Some(cursor) => Some(cursor.into_bytes()),
+    };
+
+    let since = get_u64("since")?;
+    if since.is_some()
+        && matches!(
+            method,
+            "listusers"
+                | "countusers"
+                | "listmoderators"
+                | "countmoderators"
+                | "listadmins"
+                | "listrole"
+        )
+    {
+        return Err(ChorusError::BadRequest("Users have no timestamp to filter by since").into());
+    }
+
+    let counting = method.starts_with("count");
+    Ok(ListFilter {
+        after: if counting { None } else { after },
+        prefix: get_str("prefix")?.map(|p| if hex_keys { p.to_lowercase() } else { p }),
+        since,
+        reason_contains: get_str("reason")?,
+        limit: if counting {
+            None
+        } else {
+            get_u64("limit")?.map(|l| l as usize)
+        },
+    })
+}
+
+// Root admins from the config file followed by admins from the database
+fn admin_hex_keys() -> Result<Vec<String>, Error> {
+    let mut keys = GLOBALS.config.read().admin_hex_keys.clone();
+    for pk in crate::dump_admins()?.iter() {
+        let hex = pk.as_hex_string();
+        if !keys.contains(&hex) {
+            keys.push(hex);
+        }
+    }
+    Ok(keys)
+}
+
+// Nobody may grant or revoke permissions they do not have themselves
+fn exceeds_own_permissions(own: PermissionSet, permissions: PermissionSet) -> bool {
+    !own.contains_all(permissions)
+}
+
+fn unauthorized_role() -> Result<Option<Value>, Error> {
+    Ok(Some(json!({
+        "result": {},
+        "error": "Unauthorized: That role has permissions you do not have"
+    })))
+}
+
 fn get_pubkey_param(obj: &Map<String, Value>) -> Result<Pubkey, Error> {
     let pubkey_text = obj
         .get("params")
@@ -613,3 +1235,166 @@ fn get_string_param(obj: &Map<String, Value>) -> Result<String, Error> {
         .ok_or(ChorusError::BadRequest("Parameter is not a string as expected").into_err())?
         .to_owned())
 }
+
+fn get_optional_string_param(
+    obj: &Map<String, Value>,
+    index: usize,
+) -> Result<Option<String>, Error> {
+    let params = obj
+        .get("params")
+        .ok_or(ChorusError::BadRequest("Params field missing").into_err())?
+        .as_array()
+        .ok_or(ChorusError::BadRequest("Params not an array").into_err())?;
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => Ok(Some(
+            v.as_str()
+                .ok_or(ChorusError::BadRequest("Parameter is not a string as expected").into_err())?
+                .to_owned(),
+        )),
+    }
+}
+
+fn get_string_array_param(obj: &Map<String, Value>, index: usize) -> Result<Vec<String>, Error> {
+    obj.get("params")
+        .ok_or(ChorusError::BadRequest("Params field missing").into_err())?
+        .as_array()
+        .ok_or(ChorusError::BadRequest("Params not an array").into_err())?
+        .get(index)
+        .ok_or(ChorusError::BadRequest("Missing parameter").into_err())?
+        .as_array()
+        .ok_or(ChorusError::BadRequest("Parameter is not an array as expected").into_err())?
+        .iter()
+        .map(|v| {
+            v.as_str()
+                .map(|s| s.to_owned())
+                .ok_or(ChorusError::BadRequest("Array element is not a string").into_err())
+        })
+        .collect()
+}
+
+fn get_optional_bool_param(obj: &Map<String, Value>, index: usize) -> Result<Option<bool>, Error> {
+    let params = obj
+        .get("params")
+        .ok_or(ChorusError::BadRequest("Params field missing").into_err())?
+        .as_array()
+        .ok_or(ChorusError::BadRequest("Params not an array").into_err())?;
+    match params.get(index) {
+        None | Some(Value::Null) => Ok(None),
+        Some(v) => Ok(Some(v.as_bool().ok_or(
+            ChorusError::BadRequest("Parameter is not a boolean as expected").into_err(),
+        )?)),
+    }
+}
+
+fn get_u64_param(obj: &Map<String, Value>) -> Result<u64, Error> {
+    obj.get("params")
+        .ok_or(ChorusError::BadRequest("Params field missing").into_err())?
+        .as_array()
+        .ok_or(ChorusError::BadRequest("Params not an array").into_err())?
+        .first()
+        .ok_or(ChorusError::BadRequest("Missing parameter").into_err())?
+        .as_u64()
+        .ok_or(ChorusError::BadRequest("Parameter is not a number as expected").into_err())
+}
+
+// Accepts an IP address, a CIDR network, or a hashed IP as displayed in our logs.
+// Returns the keys it may be stored under (the current key first) along with the
+// network prefix length (if a network)
+fn get_ip_param(obj: &Map<String, Value>) -> Result<(Vec<HashedIp>, Option<u8>), Error> {
+    let text = get_string_param(obj)?;
+    parse_hashed_ip(&text)
+        .ok_or_else(|| ChorusError::BadRequest("IP address could not be parsed").into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_no_privilege_escalation() {
+        let _guard = crate::test::setup_test_store();
+
+        let manager = Pubkey::read_hex(&[b'c'; 64]).unwrap();
+        let other = Pubkey::read_hex(&[b'd'; 64]).unwrap();
+        let banner: PermissionSet = [Permission::CanBan].into_iter().collect();
+        let managing: PermissionSet = [Permission::CanManageUsers].into_iter().collect();
+        crate::define_role("test-manager", managing).unwrap();
+        crate::define_role("test-banner", banner).unwrap();
+        crate::grant_role(manager, "test-manager").unwrap();
+        crate::grant_role(other, "test-banner").unwrap();
+
+        let call = |method: &str, params: Value| -> Option<Value> {
+            let command = json!({ "method": method, "params": params });
+            handle_inner(manager, crate::get_permissions(manager), command).unwrap()
+        };
+        let refused = |response: Option<Value>| {
+            response.is_some_and(|v| {
+                v["error"]
+                    .as_str()
+                    .is_some_and(|e| e.starts_with("Unauthorized"))
+            })
+        };
+        let me = manager.as_hex_string();
+        let them = other.as_hex_string();
+
+        // Built-in and custom roles with permissions the manager lacks
+        assert!(refused(call("grantrole", json!([me, "moderator"]))));
+        assert!(refused(call("grantrole", json!([me, "user"]))));
+        assert!(refused(call("grantmoderator", json!([me]))));
+        assert!(refused(call("grantuser", json!([me]))));
+        assert!(refused(call("grantrole", json!([me, "test-banner"]))));
+        assert!(refused(call("revokerole", json!([them, "test-banner"]))));
+        assert!(refused(call("revokeuser", json!([them]))));
+        assert!(refused(call("revokemoderator", json!([them]))));
+        assert_eq!(crate::get_permissions(manager), managing);
+        assert!(crate::get_permissions(other).contains_all(banner));
+
+        // Roles within the manager's own permissions
+        assert_eq!(call("grantrole", json!([them, "test-manager"])), None);
+        assert_eq!(call("revokerole", json!([them, "test-manager"])), None);
+    }
+
+    #[test]
+    fn test_refused_purge_does_not_ban() {
+        let _guard = crate::test::setup_test_store();
+
+        let banner = Pubkey::read_hex(&[b'e'; 64]).unwrap();
+        let target = Pubkey::read_hex(&[b'f'; 64]).unwrap();
+        let permissions: PermissionSet = [Permission::CanBan].into_iter().collect();
+        crate::define_role("test-ban-only", permissions).unwrap();
+        crate::grant_role(banner, "test-ban-only").unwrap();
+
+        let command = json!({
+            "method": "banpubkey",
+            "params": [target.as_hex_string(), "spam", true]
+        });
+        let response = handle_inner(banner, crate::get_permissions(banner), command)
+            .unwrap()
+            .unwrap();
+        assert!(response["error"]
+            .as_str()
+            .is_some_and(|e| e.starts_with("Unauthorized")));
+        assert_eq!(crate::get_pubkey_approval(target).unwrap(), None);
+    }
+
+    #[test]
+    fn test_list_filter() {
+        let command = json!({ "params": [{ "limit": 2, "cursor": "ab", "since": 5 }] });
+        let obj = command.as_object().unwrap();
+
+        let filter = get_list_filter(obj, "listbannedpubkeys", 0, true).unwrap();
+        assert_eq!(filter.limit, Some(2));
+        assert_eq!(filter.after, Some(vec![0xab]));
+        assert_eq!(filter.since, Some(5));
+
+        // Counts are not paginated
+        let filter = get_list_filter(obj, "countbannedpubkeys", 0, true).unwrap();
+        assert_eq!(filter.limit, None);
+        assert_eq!(filter.after, None);
+        assert_eq!(filter.since, Some(5));
+
+        // Users have no timestamps
+        assert!(get_list_filter(obj, "listusers", 0, true).is_err());
+    }
+}
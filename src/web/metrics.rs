@@ -0,0 +1,60 @@
+use crate::error::Error;
+use crate::globals::GLOBALS;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Serve metrics and health endpoints on a separate listener (see `metrics_listen_address`).
+/// Nothing else is served here, and metrics are never served on the main listener.
+pub async fn serve_metrics(listener: TcpListener) {
+    loop {
+        let (tcp_stream, _peer_addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!(target: "Server", "Metrics listener: {}", e);
+                // Avoid spinning if we are out of file descriptors
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            let io = TokioIo::new(tcp_stream);
+            let service = service_fn(handle_request);
+            if let Err(e) = GLOBALS.httpbuilder.serve_connection(io, service).await {
+                log::debug!(target: "Server", "Metrics connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?);
+    }
+
+    match request.uri().path() {
+        "/metrics" => Ok(Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .status(StatusCode::OK)
+            .body(
+                Full::new(crate::stats::render_prometheus().into())
+                    .map_err(|e| e.into())
+                    .boxed(),
+            )?),
+        "/healthz" => super::health::serve_healthz(),
+        "/readyz" => super::health::serve_readyz().await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Empty::new().map_err(|e| e.into()).boxed())?),
+    }
+}
@@ -1,5 +1,7 @@
 mod blossom;
-mod management;
+mod health;
+pub(crate) mod management;
+pub mod metrics;
 mod nip11;
 
 use crate::error::{ChorusError, Error};
@@ -53,6 +55,15 @@ pub async fn serve_http(
 
     let uri = request.uri().to_owned();
 
+    if GLOBALS.config.read().public_health_endpoints && method == Method::GET {
+        if p == "/healthz" {
+            return health::serve_healthz();
+        }
+        if p == "/readyz" {
+            return health::serve_readyz().await;
+        }
+    }
+
     if p == "/privacy-policy" {
         let config = &*GLOBALS.config.read();
         if let Some(pp) = &config.privacy_policy {
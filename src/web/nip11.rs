@@ -10,10 +10,7 @@ use hyper::{Response, StatusCode};
 
 pub async fn serve_nip11(peer: HashedPeer) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
     log::debug!(target: "Client", "{}: sent NIP-11", peer);
-    let rid = {
-        let config = &*GLOBALS.config.read();
-        GLOBALS.rid.get_or_init(|| build_rid(config))
-    };
+    let rid = relay_info_document();
 
     let response = Response::builder()
         .header("Access-Control-Allow-Origin", "*")
@@ -21,10 +18,27 @@ pub async fn serve_nip11(peer: HashedPeer) -> Result<Response<BoxBody<Bytes, Err
         .header("Access-Control-Allow-Methods", "*")
         .header("Content-Type", "application/nostr+json")
         .status(StatusCode::OK)
-        .body(Full::new(rid.clone().into()).map_err(|e| e.into()).boxed())?;
+        .body(Full::new(rid.into()).map_err(|e| e.into()).boxed())?;
     Ok(response)
 }
 
+/// The relay information document, built and cached on first use
+pub fn relay_info_document() -> String {
+    if let Some(rid) = GLOBALS.rid.read().as_ref() {
+        return rid.clone();
+    }
+
+    // Build while holding the lock, so a concurrent change (which clears the cache
+    // after it is stored) cannot leave a stale document cached
+    let mut guard = GLOBALS.rid.write();
+    if let Some(rid) = guard.as_ref() {
+        return rid.clone();
+    }
+    let rid = build_rid(&GLOBALS.config.read());
+    *guard = Some(rid.clone());
+    rid
+}
+
 fn build_rid(config: &Config) -> String {
     let mut rid: String = String::with_capacity(255);
 
@@ -69,29 +83,26 @@ fn build_rid(config: &Config) -> String {
     rid.push_str(version);
     rid.push('\"');
 
-    if let Some(name) = &config.name {
-        rid.push(',');
-        rid.push_str("\"name\":\"");
-        rid.push_str(name);
-        rid.push('\"');
-    }
-    if let Some(description) = &config.description {
-        rid.push(',');
-        rid.push_str("\"description\":\"");
-        rid.push_str(description);
-        rid.push('\"');
-    }
-    if let Some(banner_url) = &config.banner_url {
-        rid.push(',');
-        rid.push_str("\"banner\":\"");
-        rid.push_str(banner_url);
-        rid.push('\"');
-    }
-    if let Some(icon_url) = &config.icon_url {
-        rid.push(',');
-        rid.push_str("\"icon\":\"");
-        rid.push_str(icon_url);
-        rid.push('\"');
+    // Values changed via the management API override the config file
+    let overlay = |field: &str, value: &Option<String>| -> Option<String> {
+        match crate::get_relay_info(field) {
+            Ok(Some(v)) => Some(v),
+            _ => value.clone(),
+        }
+    };
+    let fields = [
+        ("name", overlay("name", &config.name)),
+        ("description", overlay("description", &config.description)),
+        ("banner", overlay("banner", &config.banner_url)),
+        ("icon", overlay("icon", &config.icon_url)),
+    ];
+    for (field, value) in fields.iter() {
+        if let Some(value) = value {
+            rid.push(',');
+            rid.push_str(&format!("\"{}\":", field));
+            // These may be set remotely, so escape them properly
+            rid.push_str(&serde_json::to_string(value).unwrap_or("\"\"".to_owned()));
+        }
     }
     if let Some(pubkey) = &config.contact_public_key {
         let mut pkh: [u8; 64] = [0; 64];
@@ -172,3 +183,30 @@ fn build_rid(config: &Config) -> String {
 
     rid
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relay_info() {
+        let _guard = crate::test::setup_test_store();
+
+        crate::set_relay_info("banner", "https://example.com/banner.png").unwrap();
+        assert_eq!(
+            crate::get_relay_info("banner").unwrap().as_deref(),
+            Some("https://example.com/banner.png")
+        );
+        let rid = relay_info_document();
+        assert!(rid.contains("\"banner\":\"https://example.com/banner.png\""));
+
+        // Changing a field rebuilds the cached document
+        crate::set_relay_info("banner", "https://example.com/other.png").unwrap();
+        assert!(relay_info_document().contains("other.png"));
+
+        // Setting it empty removes the override
+        crate::set_relay_info("banner", "").unwrap();
+        assert_eq!(crate::get_relay_info("banner").unwrap(), None);
+        assert!(!relay_info_document().contains("\"banner\""));
+    }
+}